@@ -0,0 +1,311 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    fs::{create_dir_all, read_dir, remove_file, rename, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process, slice,
+};
+
+use failure::Error;
+
+use crate::{
+    chunker::Chunker,
+    hash::{Hash, Hasher},
+    is_temp_file, TEMP_SUFFIX,
+};
+
+/// Size of the buffer used to stream data into the store.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Number of leading hex digits of a hash that name the subdirectory its blob
+/// is kept in.
+const SHARD_LEN: usize = 2;
+
+/// Where the blob `hash` is kept, relative to the directory of a store: in a
+/// subdirectory named after the start of its hash, as `ab/cdef...`, so that
+/// no one directory ends up with every blob in it. Remotes use the same
+/// layout.
+pub fn blob_path(hash: &Hash) -> String {
+    let name = hash.to_string();
+    format!("{}/{}", &name[..SHARD_LEN], &name[SHARD_LEN..])
+}
+
+/// The hash of the blob kept at `path`, relative to the directory of a store,
+/// or `None` if `path` is not where a blob is kept.
+pub fn parse_blob_path(path: &str) -> Option<Hash> {
+    match path.split_once('/') {
+        Some((shard, rest)) if shard.len() == SHARD_LEN => {
+            format!("{}{}", shard, rest).parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// A directory of blobs, each stored in a file named by the hash of its
+/// contents, as laid out by [`blob_path`]. Files are stored as a list of
+/// chunks, so that chunks shared between files or versions of a file are only
+/// stored once.
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(dir: &Path) -> BlobStore {
+        BlobStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path(&self, hash: &Hash) -> PathBuf {
+        self.dir.join(blob_path(hash))
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.path(hash).is_file()
+    }
+
+    /// Stream everything from `reader` into the store, returning the hash it
+    /// can be retrieved by. Only a fixed-size buffer is held in memory.
+    pub fn insert<R: Read>(&self, mut reader: R) -> Result<Hash, Error> {
+        create_dir_all(&self.dir)?;
+        let tmp = self
+            .dir
+            .join(format!(".incoming.{}{}", process::id(), TEMP_SUFFIX));
+
+        let mut hasher = Hasher::new();
+        let result = (|| {
+            let mut out = File::create(&tmp)?;
+            let mut buf = vec![0; BUFFER_SIZE];
+            loop {
+                let n = reader.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                out.write_all(&buf[..n])?;
+            }
+            out.sync_all()
+        })();
+        if let Err(e) = result {
+            let _ = remove_file(&tmp);
+            return Err(e.into());
+        }
+
+        let hash = hasher.finish();
+        let path = self.path(&hash);
+        if path.is_file() {
+            remove_file(&tmp)?;
+        } else {
+            if let Some(shard) = path.parent() {
+                create_dir_all(shard)?;
+            }
+            rename(&tmp, path)?;
+        }
+        Ok(hash)
+    }
+
+    /// Every blob in the store, with its size in bytes.
+    pub fn list(&self) -> Result<Vec<(Hash, u64)>, Error> {
+        let mut blobs = Vec::new();
+        if !self.dir.is_dir() {
+            return Ok(blobs);
+        }
+        for shard in read_dir(&self.dir)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            let prefix = shard.file_name().to_string_lossy().into_owned();
+            for entry in read_dir(shard.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if is_temp_file(&name) {
+                    continue;
+                }
+                if let Some(hash) = parse_blob_path(&format!("{}/{}", prefix, name)) {
+                    blobs.push((hash, entry.metadata()?.len()));
+                }
+            }
+        }
+        Ok(blobs)
+    }
+
+    /// Remove the blob `hash` from the store, if it is there.
+    pub fn remove(&self, hash: &Hash) -> Result<(), Error> {
+        match remove_file(self.path(hash)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Move the file at `path` into the store as the blob `hash`, as long as
+    /// its contents match. Returns whether they did; the file is removed
+    /// either way.
+    pub fn import(&self, hash: &Hash, path: &Path) -> Result<bool, Error> {
+        let matched = Hash::of_reader(File::open(path)?)? == *hash;
+        if matched && !self.contains(hash) {
+            self.insert(File::open(path)?)?;
+        }
+        remove_file(path)?;
+        Ok(matched)
+    }
+
+    /// Remove every blob among `hashes` whose contents no longer match its
+    /// hash, so that it is fetched again. Returns how many were removed.
+    pub fn drop_damaged(&self, hashes: &[Hash]) -> Result<usize, Error> {
+        let mut dropped = 0;
+        for hash in hashes.iter().filter(|hash| self.contains(hash)) {
+            if Hash::of_reader(self.open(hash)?)? != *hash {
+                log::warn!("dropping damaged blob {}", hash);
+                self.remove(hash)?;
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+
+    /// Open the blob `hash` for reading.
+    pub fn open(&self, hash: &Hash) -> Result<File, Error> {
+        Ok(File::open(self.path(hash))?)
+    }
+
+    /// Split everything from `reader` into chunks and add each one to the
+    /// store. Returns the hash of all of the data, along with the hashes of
+    /// its chunks in order.
+    pub fn insert_chunks<R: Read>(&self, reader: R) -> Result<(Hash, Vec<Hash>), Error> {
+        let mut hasher = Hasher::new();
+        let mut chunks = Vec::new();
+        let mut chunker = Chunker::new(reader);
+        while let Some(chunk) = chunker.next_chunk()? {
+            hasher.update(&chunk);
+            chunks.push(self.insert(chunk.as_slice())?);
+        }
+        Ok((hasher.finish(), chunks))
+    }
+
+    /// Open the concatenation of `chunks` for reading.
+    pub fn open_chunks<'a>(&'a self, chunks: &'a [Hash]) -> ChunkReader<'a> {
+        ChunkReader {
+            store: self,
+            chunks: chunks.iter(),
+            current: None,
+        }
+    }
+}
+
+/// Reads a list of chunks from a [`BlobStore`] one after another.
+pub struct ChunkReader<'a> {
+    store: &'a BlobStore,
+    chunks: slice::Iter<'a, Hash>,
+    current: Option<File>,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(file) = self.current.as_mut() {
+                let n = file.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            match self.chunks.next() {
+                Some(hash) => self.current = Some(File::open(self.store.path(hash))?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs::write};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn chunks_round_trip() {
+        let root = TempDir::new();
+        let store = BlobStore::new(&root.path().join("blobs"));
+        let data = (0..600_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect::<Vec<u8>>();
+
+        let (hash, chunks) = store.insert_chunks(data.as_slice()).unwrap();
+        assert_eq!(hash, Hash::of(&data));
+        assert!(chunks.len() > 1);
+        assert_eq!(store.insert_chunks(data.as_slice()).unwrap().1, chunks);
+
+        let mut read_back = Vec::new();
+        store
+            .open_chunks(&chunks)
+            .read_to_end(&mut read_back)
+            .unwrap();
+        assert_eq!(read_back, data);
+
+        // Each chunk is kept once, in the subdirectory named after its hash.
+        let mut listed = store.list().unwrap();
+        listed.sort();
+        listed.dedup();
+        assert_eq!(listed.len(), chunks.iter().collect::<HashSet<_>>().len());
+        for chunk in chunks.iter() {
+            let path = store.path(chunk);
+            let shard = path.parent().unwrap().file_name().unwrap();
+            assert_eq!(shard.to_string_lossy(), chunk.to_string()[..2]);
+        }
+    }
+
+    #[test]
+    fn parses_blob_paths() {
+        let hash = Hash::of(b"contents");
+        assert_eq!(parse_blob_path(&blob_path(&hash)), Some(hash));
+        for path in [
+            hash.to_string(),
+            format!("{}/{}", &hash.to_string()[..3], &hash.to_string()[3..]),
+            format!("{}/x", &hash.to_string()[..2]),
+            format!("{}/", blob_path(&hash)),
+        ] {
+            assert_eq!(parse_blob_path(&path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn import_checks_contents() {
+        let root = TempDir::new();
+        let store = BlobStore::new(&root.path().join("blobs"));
+        let incoming = root.path().join("incoming");
+        let hash = Hash::of(b"contents");
+
+        write(&incoming, "damaged").unwrap();
+        assert!(!store.import(&hash, &incoming).unwrap());
+        assert!(!store.contains(&hash));
+        assert!(!incoming.exists());
+
+        write(&incoming, "contents").unwrap();
+        assert!(store.import(&hash, &incoming).unwrap());
+        assert!(store.contains(&hash));
+        assert!(!incoming.exists());
+    }
+
+    #[test]
+    fn drops_damaged_blobs() {
+        let root = TempDir::new();
+        let store = BlobStore::new(&root.path().join("blobs"));
+        let intact = store.insert(&b"intact"[..]).unwrap();
+        let damaged = store.insert(&b"damaged"[..]).unwrap();
+        write(store.path(&damaged), "changed").unwrap();
+
+        assert_eq!(store.drop_damaged(&[intact, damaged]).unwrap(), 1);
+        assert!(store.contains(&intact));
+        assert!(!store.contains(&damaged));
+        assert_eq!(store.list().unwrap(), [(intact, 6)]);
+    }
+}
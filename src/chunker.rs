@@ -0,0 +1,188 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Content-defined chunking, following FastCDC.
+//!
+//! Chunk boundaries are picked by a rolling gear hash over the data, so an
+//! edit only changes the chunks around it and the rest of the file splits the
+//! same way it did before. The parameters here are part of the storage format:
+//! changing them changes how every file is split.
+
+use std::io::{self, Read};
+
+/// No chunk is cut shorter than this, except at the end of the data.
+pub const MIN_SIZE: usize = 16 * 1024;
+/// The size chunks are normalized towards.
+pub const AVG_SIZE: usize = 64 * 1024;
+/// No chunk is longer than this.
+pub const MAX_SIZE: usize = 256 * 1024;
+
+/// Harder to match than one in `AVG_SIZE`, used before reaching `AVG_SIZE`.
+const MASK_SMALL: u64 = !(u64::MAX >> 18);
+/// Easier to match than one in `AVG_SIZE`, used after reaching `AVG_SIZE`.
+const MASK_LARGE: u64 = !(u64::MAX >> 14);
+
+/// One pseudo-random value per byte, from splitmix64 with a fixed seed.
+const GEAR: [u64; 256] = {
+    let mut gear = [0u64; 256];
+    let mut state: u64 = 0x6d61_6770_6965_0001;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        gear[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    gear
+};
+
+/// Find the length of the first chunk in `data`, which must hold either at
+/// least `MAX_SIZE` bytes or everything that is left.
+pub fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+
+    let normal = data.len().min(AVG_SIZE);
+    let end = data.len().min(MAX_SIZE);
+    let mut hash: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Splits everything read from a reader into chunks, holding at most
+/// `MAX_SIZE` bytes in memory.
+pub struct Chunker<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Chunker<R> {
+        Chunker {
+            reader,
+            buf: Vec::with_capacity(MAX_SIZE),
+            eof: false,
+        }
+    }
+
+    /// Read the next chunk, or `None` once all of the data has been read.
+    pub fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while !self.eof && self.buf.len() < MAX_SIZE {
+            let start = self.buf.len();
+            self.buf.resize(MAX_SIZE, 0);
+            match self.reader.read(&mut self.buf[start..]) {
+                Ok(0) => {
+                    self.buf.truncate(start);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(start + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(start),
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let cut = cut_point(&self.buf);
+        let rest = self.buf.split_off(cut);
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random bytes from xorshift64, so that chunk boundaries land
+    /// somewhere other than the size limits.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks<R: Read>(reader: R) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(reader);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    /// Hands out at most `step` bytes per read.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.step).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn splits_within_limits() {
+        let data = noise(3 * 1024 * 1024 + 17, 1);
+        let chunks = chunks(data.as_slice());
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), data);
+        for chunk in chunks[..chunks.len() - 1].iter() {
+            assert!(chunk.len() >= MIN_SIZE && chunk.len() <= MAX_SIZE);
+        }
+        assert!(chunks.iter().any(|c| c.len() < MAX_SIZE));
+    }
+
+    #[test]
+    fn small_and_empty_data() {
+        assert!(chunks(&[][..]).is_empty());
+        assert_eq!(chunks(&b"short"[..]), [b"short".to_vec()]);
+    }
+
+    #[test]
+    fn independent_of_read_sizes() {
+        let data = noise(1024 * 1024, 2);
+        let whole = chunks(data.as_slice());
+        for step in [4095, 65537, 300_000] {
+            let data = Trickle { data: &data, step };
+            assert_eq!(chunks(data), whole, "reads of {} bytes", step);
+        }
+    }
+
+    #[test]
+    fn edits_only_change_nearby_chunks() {
+        let data = noise(2 * 1024 * 1024, 3);
+        let mut edited = b"a few bytes inserted up front".to_vec();
+        edited.extend_from_slice(&data);
+
+        let before = chunks(data.as_slice());
+        let after = chunks(edited.as_slice());
+        let shared = after.iter().filter(|c| before.contains(c)).count();
+        assert!(shared >= before.len() - 2, "{} of {}", shared, before.len());
+    }
+}
@@ -0,0 +1,47 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The causal context of a replica's state: for every replica, how many of
+/// the changes made there the state has seen.
+///
+/// Each change is identified by a dot, the replica it was made on and its
+/// position in that replica's sequence of changes, counting from 1. A state
+/// has seen a change exactly when its version vector covers that dot.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    pub fn new() -> VersionVector {
+        VersionVector(BTreeMap::new())
+    }
+
+    /// The number of changes made on `replica` that have been seen.
+    pub fn get(&self, replica: &str) -> u64 {
+        self.0.get(replica).copied().unwrap_or(0)
+    }
+
+    /// Record a new change on `replica`, returning its position.
+    pub fn increment(&mut self, replica: &str) -> u64 {
+        let counter = self.0.entry(replica.to_string()).or_insert(0);
+        *counter += 1;
+        *counter
+    }
+
+    /// Whether the change at `counter` on `replica` has been seen.
+    pub fn covers(&self, replica: &str, counter: u64) -> bool {
+        counter > 0 && counter <= self.get(replica)
+    }
+
+    /// Include every change seen by `other`.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (replica, &counter) in other.0.iter() {
+            let ours = self.0.entry(replica.clone()).or_insert(0);
+            *ours = (*ours).max(counter);
+        }
+    }
+}
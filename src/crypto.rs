@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! XChaCha20-Poly1305 authenticated encryption, and PBKDF2-HMAC-SHA256 for
+//! deriving keys.
+//!
+//! Sealed data is laid out as `MAGIC || nonce || ciphertext || tag`, with the
+//! name of the object bound in as associated data so that one sealed object
+//! cannot be passed off as another.
+//!
+//! A [`Key`] holds two independent keys derived from the same secret: one
+//! for sealing, and one for naming objects so that their names say nothing
+//! about their contents.
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use failure::{format_err, Error};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const MAGIC: &[u8; 8] = b"magpie\x00\x01";
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+/// A key for [`seal`] and [`open`].
+#[derive(Clone)]
+pub struct Key {
+    seal: [u8; KEY_LEN],
+    name: [u8; KEY_LEN],
+}
+
+impl Key {
+    /// Derive a key from `secret`, which may be a passphrase or the contents
+    /// of a key file, stretching it with PBKDF2 so that weak passphrases are
+    /// expensive to guess. `salt` should be random and unique to the library.
+    pub fn derive(secret: &[u8], salt: &[u8]) -> Key {
+        let master = pbkdf2::pbkdf2_hmac_array::<Sha256, KEY_LEN>(secret, salt, PBKDF2_ROUNDS);
+        Key {
+            seal: hmac_sha256(&master, b"magpie seal"),
+            name: hmac_sha256(&master, b"magpie name"),
+        }
+    }
+
+    /// A name for the object identified by `id` that cannot be linked back to
+    /// `id` without the key.
+    pub fn name(&self, id: &[u8]) -> [u8; 32] {
+        hmac_sha256(&self.name, id)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new((&self.seal).into())
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Fill `buf` with random bytes from the operating system.
+pub fn random_bytes(buf: &mut [u8]) -> Result<(), Error> {
+    OsRng
+        .try_fill_bytes(buf)
+        .map_err(|e| format_err!("no random bytes: {}", e))
+}
+
+/// Encrypt and authenticate `plaintext`, binding it to `name`.
+pub fn seal(key: &Key, name: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    random_bytes(&mut nonce)?;
+    let payload = Payload {
+        msg: plaintext,
+        aad: name,
+    };
+    let ciphertext = key
+        .cipher()
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| format_err!("encryption failed"))?;
+
+    let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Check and decrypt data produced by [`seal`] with the same key and name.
+pub fn open(key: &Key, name: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    let body = match sealed.strip_prefix(MAGIC) {
+        Some(body) if body.len() >= NONCE_LEN + TAG_LEN => body,
+        _ => return Err(format_err!("not encrypted")),
+    };
+    let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: name,
+    };
+    key.cipher()
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| format_err!("authentication failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // Sealed, and named, by the first release that encrypted anything.
+    #[test]
+    fn opens_earlier_seals() {
+        let key = Key::derive(b"secret", b"salt");
+        assert_eq!(
+            hex(&key.name(b"id")),
+            "657b40fa0779d85de017185e31bd6d28589f35ee32f3e18af4d2d9a5887f0b1b"
+        );
+        let sealed = unhex(
+            "6d6167706965000197f8482efd4b41e77ab9f967652c86c70c0793530d361b1f\
+             25a1ebb150bcd820a774f93d9d11ba8e65f6420a26c4e94b52",
+        );
+        assert_eq!(open(&key, b"name", &sealed).unwrap(), b"plaintext");
+    }
+
+    #[test]
+    fn seal_round_trips() {
+        let key = Key::derive(b"secret", b"salt");
+        let sealed = seal(&key, b"name", b"plaintext").unwrap();
+        assert_eq!(open(&key, b"name", &sealed).unwrap(), b"plaintext");
+        assert_eq!(sealed.len(), MAGIC.len() + NONCE_LEN + 9 + TAG_LEN);
+
+        // Every seal uses a fresh nonce.
+        assert_ne!(seal(&key, b"name", b"plaintext").unwrap(), sealed);
+    }
+
+    #[test]
+    fn open_refuses_tampering() {
+        let key = Key::derive(b"secret", b"salt");
+        let sealed = seal(&key, b"name", b"plaintext").unwrap();
+
+        assert!(open(&key, b"other", &sealed).is_err());
+        assert!(open(&Key::derive(b"other", b"salt"), b"name", &sealed).is_err());
+        assert!(open(&Key::derive(b"secret", b"pepper"), b"name", &sealed).is_err());
+        assert!(open(&key, b"name", b"plaintext").is_err());
+        for i in 0..sealed.len() {
+            let mut damaged = sealed.clone();
+            damaged[i] ^= 1;
+            assert!(open(&key, b"name", &damaged).is_err(), "byte {}", i);
+        }
+        for len in 0..sealed.len() {
+            assert!(
+                open(&key, b"name", &sealed[..len]).is_err(),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn names_depend_on_the_key() {
+        let key = Key::derive(b"secret", b"salt");
+        assert_eq!(key.name(b"id"), key.name(b"id"));
+        assert_ne!(key.name(b"id"), key.name(b"other"));
+        assert_ne!(
+            key.name(b"id"),
+            Key::derive(b"secret", b"pepper").name(b"id")
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    fmt,
+    io::{self, Read},
+    str::FromStr,
+};
+
+use failure::{format_err, Error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 digest, used to address data by its contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct Hash(#[serde(with = "serde_bytes")] [u8; 32]);
+
+impl Hash {
+    /// Wrap a 32-byte digest computed some other way.
+    pub fn from_bytes(bytes: [u8; 32]) -> Hash {
+        Hash(bytes)
+    }
+
+    /// Hash all of `data` at once.
+    pub fn of(data: &[u8]) -> Hash {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// Hash everything from `reader`, a buffer at a time.
+    pub fn of_reader<R: Read>(mut reader: R) -> io::Result<Hash> {
+        let mut hasher = Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(hasher.finish());
+            }
+            hasher.update(&buf[..n]);
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Hash {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Hash, Error> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(format_err!("invalid hash: {}", s));
+        }
+        let mut hash = [0u8; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)
+                .map_err(|_| format_err!("invalid hash: {}", s))?;
+        }
+        Ok(Hash(hash))
+    }
+}
+
+/// Incremental SHA-256, for data that does not fit in a single buffer.
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher(Sha256::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> Hash {
+        Hash(self.0.finalize().into())
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Hasher {
+        Hasher::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> String {
+        Hash::of(data).to_string()
+    }
+
+    // Test vectors from RFC 6234, section 8.5.
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn incremental_matches_one_shot() {
+        let data = (0..1000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        for split in [0, 1, 55, 56, 63, 64, 65, 999] {
+            let mut hasher = Hasher::new();
+            hasher.update(&data[..split]);
+            hasher.update(&data[split..]);
+            assert_eq!(hasher.finish(), Hash::of(&data), "split at {}", split);
+        }
+        assert_eq!(Hash::of_reader(data.as_slice()).unwrap(), Hash::of(&data));
+    }
+
+    #[test]
+    fn parse_round_trips() {
+        let hash = Hash::of(b"abc");
+        assert_eq!(hash.to_string().parse::<Hash>().unwrap(), hash);
+        assert!("abc".parse::<Hash>().is_err());
+        assert!("zz".repeat(32).parse::<Hash>().is_err());
+    }
+}
@@ -3,18 +3,37 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use std::{
+    collections::{BTreeMap, HashSet},
     env::current_dir,
-    fs::{copy, create_dir_all, File},
+    ffi::OsString,
+    fs::{
+        create_dir_all, read, read_dir, read_to_string, remove_dir_all, remove_file, rename, File,
+        OpenOptions, TryLockError,
+    },
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
     path::{Path, PathBuf},
-    process::Command,
+    process,
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
 };
 
-use ciborium::{de::from_reader, ser::into_writer};
+use ciborium::{de::from_reader, ser::into_writer, value::Value};
 use failure::{format_err, Error};
 use serde::{de::DeserializeOwned, Serialize};
 use xdg::BaseDirectories;
 
+use crate::{blobs::BlobStore, clock::VersionVector, hash::Hash, transport::Transport};
+
+pub mod blobs;
+pub mod chunker;
+pub mod clock;
+pub mod crypto;
+pub mod hash;
 pub mod library;
+pub mod transport;
+
+#[cfg(test)]
+mod testing;
 
 pub struct EnvVars {
     pub appname: String,
@@ -22,7 +41,12 @@ pub struct EnvVars {
     pub xdg_dirs: BaseDirectories,
     pub crdt: PathBuf,
     pub data: PathBuf,
+    pub blobs: BlobStore,
+    /// Where the remote is. The remote is made up of files and directories
+    /// named after the url with a suffix, rather than the single state file
+    /// at the url that the first releases used.
     pub url: String,
+    pub lock_timeout: Option<Duration>,
 }
 
 impl EnvVars {
@@ -33,6 +57,11 @@ impl EnvVars {
         let xdg_dirs = BaseDirectories::with_profile(&appname, &channel)?;
         let crdt = xdg_dirs.get_data_file("local.cbor");
         let data = current_dir()?;
+        let blobs = BlobStore::new(&xdg_dirs.get_data_file("blobs"));
+        let lock_timeout = match std::env::var("lock_timeout") {
+            Ok(secs) => Some(Duration::from_secs(secs.parse()?)),
+            Err(_) => None,
+        };
 
         Ok(EnvVars {
             appname,
@@ -40,104 +69,1840 @@ impl EnvVars {
             xdg_dirs,
             crdt,
             data,
+            blobs,
             url,
+            lock_timeout,
+        })
+    }
+
+    /// Take the lock on this profile, which must be held while changing its
+    /// local state. If another process holds it, wait for up to
+    /// `lock_timeout` before giving up.
+    ///
+    /// The lock lives in the data directory rather than the runtime one, which
+    /// is not set up for every session, so that a cron job and an interactive
+    /// shell always contend for the same file.
+    pub fn lock(&self) -> Result<ProfileLock, Error> {
+        let path = self.xdg_dirs.place_data_file("lock")?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+
+        let deadline = Instant::now() + self.lock_timeout.unwrap_or_default();
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(ProfileLock { _file: file }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    log::trace!("waiting for {}", path.display());
+                    sleep(LOCK_POLL_INTERVAL);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(format_err!(
+                        "{} is held by another magpie process",
+                        path.display()
+                    ))
+                }
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// The id of this replica, which names its state on the remote and is
+    /// recorded against every change made here. It is generated by `init`, or
+    /// the first time it is needed by a profile that predates it.
+    pub fn replica(&self) -> Result<String, Error> {
+        let path = self.xdg_dirs.get_data_file("replica");
+        match read_to_string(&path) {
+            Ok(replica) => Ok(replica.trim().to_string()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let replica = unique_id()?;
+                write_atomic(&self.xdg_dirs.place_data_file("replica")?, |out| {
+                    out.write_all(replica.as_bytes())?;
+                    Ok(())
+                })?;
+                Ok(replica)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the id of this replica with a new one, so that changes made
+    /// from here on are never confused with ones made under the old id. The
+    /// old id is retired: its state is removed from the remote on the next
+    /// sync.
+    fn rekey(&self) -> Result<String, Error> {
+        let old = self.replica()?;
+        let mut retired = self.retired()?;
+        retired.push(old);
+        self.set_retired(&retired)?;
+
+        let replica = unique_id()?;
+        write_atomic(&self.xdg_dirs.place_data_file("replica")?, |out| {
+            out.write_all(replica.as_bytes())?;
+            Ok(())
+        })?;
+        Ok(replica)
+    }
+
+    /// The old ids of this replica whose state may still be on the remote.
+    fn retired(&self) -> Result<Vec<String>, Error> {
+        match read_to_string(self.xdg_dirs.get_data_file("retired")) {
+            Ok(retired) => Ok(retired.lines().map(str::to_string).collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_retired(&self, retired: &[String]) -> Result<(), Error> {
+        let path = self.xdg_dirs.get_data_file("retired");
+        if retired.is_empty() {
+            if path.is_file() {
+                remove_file(&path)?;
+            }
+            return Ok(());
+        }
+
+        write_atomic(&path, |out| {
+            for replica in retired {
+                writeln!(out, "{}", replica)?;
+            }
+            Ok(())
         })
     }
 
+    /// Save a snapshot of the local state to the history before it is
+    /// overwritten, along with the state of every replica in `replicas`, and
+    /// drop the oldest snapshots past [`HISTORY_LIMIT`].
+    fn save_history(&self, replicas: Option<&Path>) -> Result<(), Error> {
+        if !self.crdt.is_file() {
+            return Ok(());
+        }
+
+        // The snapshot is put together under a temporary name, so that it
+        // only shows up in the history once it is complete.
+        let history = self.xdg_dirs.create_data_directory("history")?;
+        let id = unique_id()?;
+        let staging = history.join(format!(".{}{}", id, TEMP_SUFFIX));
+        create_dir_all(&staging)?;
+        copy_atomic(&self.crdt, &staging.join(SNAPSHOT_STATE))?;
+        if let Some(replicas) = replicas {
+            let dest = staging.join(SNAPSHOT_REPLICAS);
+            create_dir_all(&dest)?;
+            for entry in read_dir(replicas)? {
+                let entry = entry?;
+                if !is_temp_file(&entry.file_name().to_string_lossy()) {
+                    copy_atomic(&entry.path(), &dest.join(entry.file_name()))?;
+                }
+            }
+        }
+        rename(&staging, history.join(&id))?;
+
+        let snapshots = snapshots(self)?;
+        if snapshots.len() > HISTORY_LIMIT {
+            for snapshot in snapshots[..snapshots.len() - HISTORY_LIMIT].iter() {
+                log::trace!("dropping snapshot {}", snapshot.id);
+                remove_dir_all(history.join(&snapshot.id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the entries rejected by the last sync, and those it could not
+    /// unpack, so they can be looked at after the fact. Each one is prefixed
+    /// with the replica it came from.
+    fn report_quarantine(&self, rejected: &[String]) -> Result<(), Error> {
+        let path = self.xdg_dirs.get_data_file("quarantine");
+        if rejected.is_empty() {
+            if path.is_file() {
+                remove_file(&path)?;
+            }
+            return Ok(());
+        }
+
+        write_atomic(&path, |out| {
+            for entry in rejected {
+                writeln!(out, "{}", entry)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The secret that the key used to encrypt everything sent to the remote
+    /// is derived from: the contents of the `key` file in the config
+    /// directory. Without one, the remote is sent plain data.
+    pub fn secret(&self) -> Result<Option<Vec<u8>>, Error> {
+        match self.xdg_dirs.find_config_file("key") {
+            Some(path) => Ok(Some(read(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the state left at the url by the first releases has been
+    /// merged into the local state, or found not to be there.
+    fn legacy_merged(&self) -> bool {
+        self.xdg_dirs.find_data_file("legacy-merged").is_some()
+    }
+
+    fn set_legacy_merged(&self) -> Result<(), Error> {
+        write_atomic(&self.xdg_dirs.place_data_file("legacy-merged")?, |_| Ok(()))
+    }
+
+    /// The state left at the url of the remote by the first releases, which
+    /// shared a single state file between every replica, if it is still
+    /// there.
+    fn legacy_state<D: CrdtPack>(
+        &self,
+        transport: &dyn Transport,
+        dir: &Path,
+    ) -> Result<Option<D>, Error> {
+        let path = dir.join("remote.cbor");
+        log::trace!("pulling {}", path.display());
+        if !transport.pull_legacy(&path)? {
+            return Ok(None);
+        }
+        let (state, _) = from_file(&path, Some(self))?;
+        Ok(Some(state))
+    }
+
+    /// The directory holding the last state pulled from every replica.
     fn remote_cache(&self) -> Result<PathBuf, Error> {
-        Ok(self.xdg_dirs.place_cache_file("remote.cbor")?)
+        Ok(self.xdg_dirs.create_cache_directory("replicas")?)
+    }
+}
+
+/// How many snapshots of earlier local states are kept in the history.
+const HISTORY_LIMIT: usize = 20;
+/// Name of the local state within a snapshot in the history.
+const SNAPSHOT_STATE: &str = "local.cbor";
+/// Name of the directory holding the replica states within a snapshot.
+const SNAPSHOT_REPLICAS: &str = "replicas";
+
+/// A snapshot of an earlier local state, saved to the history before it was
+/// overwritten.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub id: String,
+    /// When the local state was replaced.
+    pub time: SystemTime,
+    /// How many replicas had their state saved along with it, if any.
+    pub replicas: usize,
+}
+
+/// Every snapshot in the history of the profile in `vars`, oldest first.
+pub fn snapshots(vars: &EnvVars) -> Result<Vec<Snapshot>, Error> {
+    let history = match vars.xdg_dirs.find_data_file("history") {
+        Some(history) => history,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut snapshots = Vec::new();
+    for entry in read_dir(history)? {
+        let entry = entry?;
+        let id = entry.file_name().to_string_lossy().into_owned();
+        let state = entry.path().join(SNAPSHOT_STATE);
+        if is_temp_file(&id) || !state.is_file() {
+            continue;
+        }
+        // Each replica may have saved deltas alongside its snapshot.
+        let replicas = entry.path().join(SNAPSHOT_REPLICAS);
+        let replicas = if replicas.is_dir() {
+            replica_files(&replicas)?.len()
+        } else {
+            0
+        };
+        snapshots.push(Snapshot {
+            id,
+            time: state.metadata()?.modified()?,
+            replicas,
+        });
     }
+    snapshots.sort_by(|a, b| (a.time, &a.id).cmp(&(b.time, &b.id)));
+    Ok(snapshots)
 }
 
-fn from_file<D: DeserializeOwned>(path: &Path) -> Result<D, Error> {
-    let buf = File::open(path)?;
-    let data: D = from_reader(buf)?;
-    Ok(data)
+/// How often to retry while waiting for a [`ProfileLock`].
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An advisory lock on a profile, released when dropped.
+pub struct ProfileLock {
+    _file: File,
+}
+
+/// Generate a random identifier, unique across processes and machines.
+pub(crate) fn unique_id() -> Result<String, Error> {
+    let mut id = [0u8; 16];
+    crypto::random_bytes(&mut id)?;
+    Ok(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Marks the start of every serialized state file.
+const STATE_MAGIC: &[u8; 8] = b"magpie\x00S";
+/// Version of the envelope that serialized state is wrapped in. Format 1 had
+/// no schema version, and is read as schema 1. Before format 3 the payload held
+/// only the state, with no version vector.
+const STATE_FORMAT: u32 = 3;
+
+/// A state file whose envelope has been checked, but whose payload has not
+/// been deserialized yet.
+struct Envelope {
+    schema: u32,
+    context: VersionVector,
+    payload: Vec<u8>,
 }
 
-fn to_file<S: Serialize>(path: &Path, data: &S) -> Result<(), Error> {
-    let out = File::create(path)?;
-    into_writer(&data, &out)?;
+/// Read the payload of a state file written by [`to_file`], checking that it
+/// is complete and unchanged before handing any of it back. If `bare` is set,
+/// a file with no envelope at all is accepted as schema 0, from before state
+/// files had one.
+fn read_envelope(path: &Path, bare: bool) -> Result<Envelope, Error> {
+    let mut buf = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut buf)?;
+    if !buf.starts_with(STATE_MAGIC) {
+        if bare {
+            return Ok(Envelope {
+                schema: 0,
+                context: VersionVector::new(),
+                payload: buf,
+            });
+        }
+        return Err(format_err!("{}: not a magpie state file", path.display()));
+    }
+
+    let truncated = || format_err!("{}: truncated state header", path.display());
+    let mut header = &buf[STATE_MAGIC.len()..];
+    let mut take = |n: usize| -> Result<&[u8], Error> {
+        if header.len() < n {
+            return Err(truncated());
+        }
+        let (field, rest) = header.split_at(n);
+        header = rest;
+        Ok(field)
+    };
+
+    let format = u32::from_be_bytes(take(4)?.try_into()?);
+    let schema = match format {
+        1 => 1,
+        2 | 3 => u32::from_be_bytes(take(4)?.try_into()?),
+        _ => {
+            return Err(format_err!(
+                "{}: unsupported state format {}",
+                path.display(),
+                format
+            ))
+        }
+    };
+    let length = u64::from_be_bytes(take(8)?.try_into()?);
+    let hash = take(32)?.to_vec();
+
+    let payload = header.to_vec();
+    if payload.len() as u64 != length {
+        return Err(format_err!(
+            "{}: expected {} bytes of state, found {}",
+            path.display(),
+            length,
+            payload.len()
+        ));
+    }
+    if Hash::of(&payload).as_bytes()[..] != hash[..] {
+        return Err(format_err!("{}: checksum mismatch", path.display()));
+    }
+    if format < 3 {
+        return Ok(Envelope {
+            schema,
+            context: VersionVector::new(),
+            payload,
+        });
+    }
+
+    let mut state = payload.as_slice();
+    let context = from_reader(&mut state)?;
+    Ok(Envelope {
+        schema,
+        context,
+        payload: state.to_vec(),
+    })
+}
+
+/// Load a state file and its version vector, upgrading the state to the
+/// current schema of `D` if it was written with an older one. If `legacy` is
+/// set, a bare state from before state files had an envelope is accepted
+/// too, and read with [`CrdtPack::from_bare`].
+fn from_file<D: CrdtPack>(
+    path: &Path,
+    legacy: Option<&EnvVars>,
+) -> Result<(D, VersionVector), Error> {
+    let envelope = read_envelope(path, legacy.is_some())?;
+    if let (0, Some(vars)) = (envelope.schema, legacy) {
+        log::info!("upgrading {} from the bare format", path.display());
+        let state = from_reader(envelope.payload.as_slice())?;
+        return Ok((D::from_bare(vars, state)?, envelope.context));
+    }
+    if envelope.schema == D::SCHEMA_VERSION {
+        let data: D = from_reader(envelope.payload.as_slice())?;
+        return Ok((data, envelope.context));
+    }
+    if envelope.schema > D::SCHEMA_VERSION {
+        return Err(format_err!(
+            "{}: written with schema {}, newer than the supported {}",
+            path.display(),
+            envelope.schema,
+            D::SCHEMA_VERSION
+        ));
+    }
+
+    let mut state: Value = from_reader(envelope.payload.as_slice())?;
+    for schema in envelope.schema..D::SCHEMA_VERSION {
+        log::info!(
+            "upgrading {} from schema {} to {}",
+            path.display(),
+            schema,
+            schema + 1
+        );
+        state = D::migrate(schema, state)?;
+    }
+    Ok((state.deserialized()?, envelope.context))
+}
+
+/// Suffix of the temporary files used by [`write_atomic`].
+pub(crate) const TEMP_SUFFIX: &str = ".magpie-tmp";
+
+/// Whether `filename` is one of the temporary files left by [`write_atomic`].
+pub(crate) fn is_temp_file(filename: &str) -> bool {
+    filename.starts_with('.') && filename.ends_with(TEMP_SUFFIX)
+}
+
+/// Write `path` by way of a temporary file in the same directory that is
+/// synced and then renamed over it, so that `path` always holds either the
+/// old or the new contents in full.
+pub(crate) fn write_atomic<F>(path: &Path, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut File) -> Result<(), Error>,
+{
+    let mut tmpname = OsString::from(".");
+    tmpname.push(path.file_name().unwrap_or_default());
+    tmpname.push(format!(".{}{}", process::id(), TEMP_SUFFIX));
+    let tmp = path.with_file_name(tmpname);
+
+    let mut file = File::create(&tmp)?;
+    let result = write(&mut file)
+        .and_then(|_| Ok(file.sync_all()?))
+        .and_then(|_| Ok(rename(&tmp, path)?));
+    if let Err(e) = result {
+        let _ = remove_file(&tmp);
+        return Err(e);
+    }
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
     Ok(())
 }
 
-fn load_file<D: DeserializeOwned + CrdtPack>(vars: &EnvVars) -> Result<D, Error> {
-    let mut data = from_file(&vars.crdt)?;
-    CrdtPack::pack(vars, &mut data)?;
+/// Atomically replace `dest` with a copy of `src`.
+pub(crate) fn copy_atomic(src: &Path, dest: &Path) -> Result<(), Error> {
+    write_atomic(dest, |out| {
+        io::copy(&mut File::open(src)?, out)?;
+        Ok(())
+    })
+}
+
+fn to_file<S: CrdtPack>(path: &Path, data: &S, context: &VersionVector) -> Result<(), Error> {
+    let mut payload = Vec::new();
+    into_writer(context, &mut payload)?;
+    into_writer(&data, &mut payload)?;
+
+    write_atomic(path, |out| {
+        let mut out = BufWriter::new(out);
+        out.write_all(STATE_MAGIC)?;
+        out.write_all(&STATE_FORMAT.to_be_bytes())?;
+        out.write_all(&S::SCHEMA_VERSION.to_be_bytes())?;
+        out.write_all(&(payload.len() as u64).to_be_bytes())?;
+        out.write_all(Hash::of(&payload).as_bytes())?;
+        out.write_all(&payload)?;
+        out.flush()?;
+        Ok(())
+    })
+}
+
+/// Remove the state files of the retired ids of the replica in `vars` from
+/// `dir`, so that they are neither merged nor pushed.
+fn drop_retired(vars: &EnvVars, dir: &Path) -> Result<Vec<String>, Error> {
+    let retired = vars.retired()?;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if retired
+            .iter()
+            .any(|replica| is_replica_file(&name, replica))
+        {
+            remove_file(entry.path())?;
+        }
+    }
+    Ok(retired)
+}
+
+/// Extension of the files holding a replica's changes since its snapshot,
+/// named `<replica>.<id>.delta`. Unlike snapshots, deltas never change once
+/// written.
+pub(crate) const DELTA_EXTENSION: &str = "delta";
+
+/// How many deltas a replica pushes before replacing them with a snapshot.
+const MAX_DELTAS: usize = 16;
+
+/// Whether `filename` is one of the state files pushed by `replica`.
+pub(crate) fn is_replica_file(filename: &str, replica: &str) -> bool {
+    filename
+        .strip_prefix(replica)
+        .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// The state files pushed by one replica: a snapshot of its full state, and
+/// the deltas pushed since.
+#[derive(Default)]
+struct ReplicaFiles {
+    snapshot: Option<PathBuf>,
+    deltas: Vec<PathBuf>,
+}
 
-    Ok(data)
+/// Group the state files in `dir` by the replica that pushed them.
+fn replica_files(dir: &Path) -> Result<BTreeMap<String, ReplicaFiles>, Error> {
+    let mut replicas = BTreeMap::<String, ReplicaFiles>::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if !is_temp_file(name) => name,
+            _ => continue,
+        };
+        let (replica, extension) = match (name.split_once('.'), name.rsplit_once('.')) {
+            (Some((replica, _)), Some((_, extension))) => (replica, extension),
+            _ => continue,
+        };
+        let files = replicas.entry(replica.to_string()).or_default();
+        if name == format!("{}.cbor", replica) {
+            files.snapshot = Some(path);
+        } else if extension == DELTA_EXTENSION {
+            files.deltas.push(path);
+        }
+    }
+    Ok(replicas)
+}
+
+/// Rebuild the state pushed by one replica from its snapshot and deltas.
+fn load_replica<D: CrdtPack>(files: &ReplicaFiles) -> Result<(D, VersionVector), Error> {
+    // Deltas carry the version vector of the full state they were taken
+    // from, so they are only meaningful on top of a snapshot.
+    let snapshot = files
+        .snapshot
+        .as_ref()
+        .ok_or_else(|| format_err!("deltas without a snapshot"))?;
+    let (mut state, mut context): (D, _) = from_file(snapshot, None)?;
+    for path in files.deltas.iter() {
+        let (delta, delta_context) = from_file(path, None)?;
+        state.apply_delta(&context, delta, &delta_context);
+        context.merge(&delta_context);
+    }
+    Ok((state, context))
+}
+
+/// Merge the state of every replica cached in `cache_dir` into `local`, and
+/// their version vectors into `context`, except for `skip`. Returns a
+/// description of every state or entry that had to be left out.
+pub(crate) fn merge_cached<D: CrdtPack>(
+    cache_dir: &Path,
+    skip: Option<&str>,
+    local: &mut D,
+    context: &mut VersionVector,
+) -> Result<Vec<String>, Error> {
+    let mut quarantine = Vec::new();
+    for (other, files) in replica_files(cache_dir)? {
+        if Some(other.as_str()) == skip {
+            continue;
+        }
+
+        // A damaged state is left out of the merge entirely, rather than
+        // merged in and pushed back out to every other replica.
+        let (mut remote, remote_context) = match load_replica::<D>(&files) {
+            Ok(remote) => remote,
+            Err(e) => {
+                log::error!("refusing state from replica {}: {}", other, e);
+                quarantine.push(format!("{}: {}", other, e));
+                continue;
+            }
+        };
+        let mut rejected = remote.take_rejected();
+        log::trace!("merging replica {}", other);
+        local.merge(context, remote, &remote_context);
+        context.merge(&remote_context);
+        rejected.extend(local.take_rejected());
+        for rejected in rejected {
+            log::warn!("rejected entry from replica {}: {}", other, rejected);
+            quarantine.push(format!("{}: {}", other, rejected));
+        }
+    }
+    Ok(quarantine)
+}
+
+/// Record `local`, the state in `vars`, as the next files for `replica` to
+/// push from `cache_dir`, which holds the files it pushed before. This is a
+/// delta of the changes since those files if possible, or else a snapshot
+/// that replaces them.
+fn stage<D: CrdtPack>(
+    vars: &EnvVars,
+    cache_dir: &Path,
+    replica: &str,
+    local: &D,
+    context: &VersionVector,
+) -> Result<(), Error> {
+    let pushed = replica_files(cache_dir)?
+        .remove(replica)
+        .unwrap_or_default();
+    if pushed.snapshot.is_some() && pushed.deltas.len() < MAX_DELTAS {
+        let mut pushed_context = VersionVector::new();
+        let mut readable = true;
+        for path in pushed.snapshot.iter().chain(pushed.deltas.iter()) {
+            match read_envelope(path, false) {
+                Ok(envelope) => pushed_context.merge(&envelope.context),
+                Err(e) => {
+                    log::warn!("replacing damaged state: {}", e);
+                    readable = false;
+                }
+            }
+        }
+
+        if let (true, Some(delta)) = (readable, local.delta_since(&pushed_context)) {
+            if pushed_context == *context {
+                log::trace!("nothing new to push");
+                return Ok(());
+            }
+            let name = format!("{}.{}.{}", replica, unique_id()?, DELTA_EXTENSION);
+            log::trace!("staging delta {}", name);
+            return to_file(&cache_dir.join(name), &delta, context);
+        }
+    }
+
+    stage_snapshot(vars, cache_dir, replica, &pushed.deltas)
+}
+
+/// Record the state in `vars` as a snapshot for `replica` to push from
+/// `cache_dir`, replacing the `deltas` it pushed before.
+fn stage_snapshot(
+    vars: &EnvVars,
+    cache_dir: &Path,
+    replica: &str,
+    deltas: &[PathBuf],
+) -> Result<(), Error> {
+    log::trace!("staging snapshot");
+    copy_atomic(&vars.crdt, &cache_dir.join(format!("{}.cbor", replica)))?;
+    for path in deltas.iter() {
+        remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Exit status of a `magpie-library-*` binary that did what it was asked,
+/// but found the library in need of attention: conflicts left by a sync, or
+/// changes that have yet to be synced. Every binary exits with status 1 when
+/// it fails.
+pub const EXIT_UNSETTLED: u8 = 2;
+
+/// The result of a successful sync.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncStatus {
+    /// Every replica agrees on the contents of the pack.
+    Clean,
+    /// The merged pack holds divergent versions that need to be resolved by
+    /// hand.
+    Conflicted,
+}
+
+/// What a sync would do, as worked out by a dry run. Every list is sorted.
+#[derive(Debug, Default)]
+pub struct SyncPlan {
+    /// Entries that would be packed from the data directory.
+    pub add: Vec<String>,
+    /// Entries that would be written to the data directory.
+    pub unpack: Vec<String>,
+    /// Entries that would be deleted from the data directory.
+    pub remove: Vec<String>,
+    /// Entries whose local changes would be pushed to the other replicas.
+    pub push: Vec<String>,
+    /// Entries that would be left in conflict.
+    pub conflicts: Vec<String>,
+}
+
+/// What a garbage collection reclaimed.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Removed entries purged from the local state.
+    pub purged: usize,
+    /// How much smaller the local state is, in bytes.
+    pub state_bytes: u64,
+    /// Blobs deleted from the local blob store, and their total size.
+    pub local_blobs: usize,
+    pub local_bytes: u64,
+    /// Blobs deleted from the remote, and their total size there.
+    pub remote_blobs: usize,
+    pub remote_bytes: u64,
 }
 
 pub trait CrdtPack: DeserializeOwned + Serialize {
+    /// Version of the layout of the serialized state, stored alongside it.
+    /// Bump this whenever the layout changes, and teach `migrate` to upgrade
+    /// from the previous version.
+    const SCHEMA_VERSION: u32;
+
     fn new() -> Self;
-    fn unpack(vars: &EnvVars, pack: &Self) -> Result<(), Error>;
-    fn pack(vars: &EnvVars, pack: &mut Self) -> Result<(), Error>;
-    fn merge(&mut self, other: Self);
+
+    /// Bring the data directory in `vars` in line with `pack`. Entries that
+    /// cannot be written are skipped, and described in the result.
+    fn unpack(vars: &EnvVars, pack: &Self) -> Result<Vec<String>, Error>;
+
+    /// Record the changes made to the data directory in `vars`, with a dot
+    /// from `context` for each.
+    fn pack(vars: &EnvVars, pack: &mut Self, context: &mut VersionVector) -> Result<(), Error>;
+
+    /// Merge in the state of another replica. `context` and `other_context`
+    /// are the version vectors of `self` and `other`, which tell a change the
+    /// other replica has not seen yet apart from one it has seen and undone.
+    fn merge(&mut self, context: &VersionVector, other: Self, other_context: &VersionVector);
+
+    /// The changes to this state that `context` has not seen, as a state of
+    /// their own, or `None` if only full states are supported.
+    fn delta_since(&self, _context: &VersionVector) -> Option<Self> {
+        None
+    }
+
+    /// Apply a delta taken by `delta_since` from a later version of this
+    /// state, with the version vector `delta_context` of that later state.
+    fn apply_delta(&mut self, context: &VersionVector, delta: Self, delta_context: &VersionVector) {
+        self.merge(context, delta, delta_context)
+    }
+
+    /// Work out what a sync would do to the data directory in `vars` given
+    /// the `local` state, before packing, and the merged state of every
+    /// replica on the remote in `remote`, each with its version vector,
+    /// without changing anything.
+    fn plan(
+        vars: &EnvVars,
+        local: (&Self, &VersionVector),
+        remote: (&Self, &VersionVector),
+    ) -> Result<SyncPlan, Error>;
+
+    /// Every blob that must be present in the blob store to unpack this pack.
+    fn blobs(&self) -> HashSet<Hash> {
+        HashSet::new()
+    }
+
+    /// Read `state` as it was written bare by the first releases, with no
+    /// schema version, storing anything it held inline in `vars`.
+    fn from_bare(_vars: &EnvVars, _state: Value) -> Result<Self, Error> {
+        Err(format_err!("cannot upgrade state with no schema"))
+    }
+
+    /// Upgrade `state`, serialized with schema version `schema`, to the layout
+    /// of version `schema + 1`.
+    fn migrate(schema: u32, _state: Value) -> Result<Value, Error> {
+        Err(format_err!("cannot upgrade state from schema {}", schema))
+    }
+
+    /// Roll the data directory in `vars` back from the `current` state to the
+    /// `restored` one.
+    fn rollback(vars: &EnvVars, _current: &Self, restored: &Self) -> Result<(), Error> {
+        Self::unpack(vars, restored)?;
+        Ok(())
+    }
+
+    /// Drop every removed entry whose removal has been seen by each of
+    /// `contexts`, returning how many were dropped. Replicas that have seen
+    /// the removal follow along when they merge this state in.
+    fn purge(&mut self, _contexts: &[VersionVector]) -> usize {
+        0
+    }
+
+    /// Describe every entry that currently holds divergent versions.
+    fn conflicts(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Describe every entry from another replica that `merge` refused to
+    /// accept since the last call.
+    fn take_rejected(&mut self) -> Vec<String> {
+        Vec::new()
+    }
 
     fn init() -> Result<(), failure::Error> {
         let vars = EnvVars::new()?;
+        create_dir_all(vars.crdt.parent().unwrap())?;
+        let _lock = vars.lock()?;
+        let replica = vars.replica()?;
         if vars.crdt.is_file() {
             return Ok(());
         }
 
-        create_dir_all(vars.crdt.parent().unwrap())?;
-
+        log::info!("initializing replica {}", replica);
         let crdt = Self::new();
-        let crdt_buf = File::create(vars.crdt)?;
-        into_writer(&crdt, crdt_buf)?;
+        to_file(&vars.crdt, &crdt, &VersionVector::new())?;
 
         Ok(())
     }
 
-    fn sync() -> Result<(), failure::Error> {
+    /// Replace the local state with the snapshot `id` from the history, and
+    /// if `data` is set, roll the data directory back to match it.
+    ///
+    /// The state being replaced is saved to the history first. The replica
+    /// takes on a new id, and its state under the old id is removed from the
+    /// remote on the next sync, so that the changes rolled back only return
+    /// if other replicas have merged them already.
+    fn restore(id: &str, data: bool) -> Result<(), failure::Error> {
+        Self::restore_with(&EnvVars::new()?, id, data)
+    }
+
+    /// Restore snapshot `id` in the history of `vars`, as `restore` does.
+    fn restore_with(vars: &EnvVars, id: &str, data: bool) -> Result<(), failure::Error> {
+        let _lock = vars.lock()?;
+
+        let snapshot = snapshots(vars)?
+            .into_iter()
+            .find(|snapshot| snapshot.id == id)
+            .ok_or_else(|| format_err!("no snapshot {} in the history", id))?;
+        let path = vars
+            .xdg_dirs
+            .get_data_file("history")
+            .join(&snapshot.id)
+            .join(SNAPSHOT_STATE);
+        let (restored, context): (Self, _) = from_file(&path, Some(vars))?;
+        let (current, _): (Self, _) = from_file(&vars.crdt, Some(vars))?;
+
+        vars.save_history(None)?;
+        if data {
+            log::info!("rolling back data directory");
+            Self::rollback(vars, &current, &restored)?;
+        }
+
+        let old = vars.replica()?;
+        let replica = vars.rekey()?;
+        log::info!("retiring replica {} in favor of {}", old, replica);
+        drop_retired(vars, &vars.remote_cache()?)?;
+
+        log::info!("restoring snapshot {}", id);
+        to_file(&vars.crdt, &restored, &context)?;
+        Ok(())
+    }
+
+    /// Purge the removals that every replica on the remote has seen, then
+    /// delete the blobs that no replica refers to anymore from the remote, and
+    /// those that the history does not refer to either from the blob store.
+    fn gc() -> Result<GcReport, failure::Error> {
+        let vars = EnvVars::new()?;
+        let transport = transport::open(&vars)?;
+        Self::gc_with(&vars, transport.as_ref())
+    }
+
+    /// Collect garbage in the local state in `vars` and the remote reached via
+    /// `transport`.
+    ///
+    /// A blob pushed by a sync that is still under way elsewhere may be
+    /// deleted before the state referring to it arrives; that sync pushes it
+    /// again next time.
+    fn gc_with(vars: &EnvVars, transport: &dyn Transport) -> Result<GcReport, failure::Error> {
+        let _lock = vars.lock()?;
+        let (mut local, context): (Self, _) = from_file(&vars.crdt, Some(vars))?;
+
+        let replica = vars.replica()?;
+        let cache_dir = vars.remote_cache()?;
+        log::trace!("pulling {}", &cache_dir.display());
+        transport.pull(&cache_dir)?;
+        drop_retired(vars, &cache_dir)?;
+
+        // Only a removal every replica has seen can be purged, so a state that
+        // cannot be read stops the collection instead of being left out.
+        let mut states = Vec::new();
+        let mut contexts = vec![context.clone()];
+        for (other, files) in replica_files(&cache_dir)? {
+            if other == replica {
+                continue;
+            }
+            let (state, other_context) = load_replica::<Self>(&files)
+                .map_err(|e| format_err!("cannot read state of replica {}: {}", other, e))?;
+            states.push(state);
+            contexts.push(other_context);
+        }
+
+        let mut report = GcReport {
+            purged: local.purge(&contexts),
+            ..GcReport::default()
+        };
+        if report.purged > 0 {
+            log::info!("purging {} removed entries", report.purged);
+            let before = vars.crdt.metadata()?.len();
+            vars.save_history(Some(&cache_dir))?;
+            to_file(&vars.crdt, &local, &context)?;
+            report.state_bytes = before.saturating_sub(vars.crdt.metadata()?.len());
+
+            // A delta cannot tell the other replicas what was purged.
+            let pushed = replica_files(&cache_dir)?
+                .remove(&replica)
+                .unwrap_or_default();
+            stage_snapshot(vars, &cache_dir, &replica, &pushed.deltas)?;
+            log::trace!("pushing state of replica {}", replica);
+            transport.push(&cache_dir, &replica)?;
+        }
+
+        let mut referenced = local.blobs();
+        for state in states.iter() {
+            referenced.extend(state.blobs());
+        }
+
+        let names = referenced
+            .iter()
+            .map(|hash| transport.blob_name(hash))
+            .collect::<HashSet<Hash>>();
+        let mut orphans = Vec::new();
+        for (name, size) in transport.list_blobs()? {
+            if !names.contains(&name) {
+                orphans.push(name);
+                report.remote_blobs += 1;
+                report.remote_bytes += size;
+            }
+        }
+        log::trace!("deleting {} blobs from the remote", orphans.len());
+        let scratch = vars.xdg_dirs.create_cache_directory("gc")?;
+        transport.remove_blobs(&orphans, &scratch)?;
+
+        // Blobs referred to from the history are kept locally, so that every
+        // snapshot there can still be restored with its data.
+        let history = vars.xdg_dirs.get_data_file("history");
+        for snapshot in snapshots(vars)? {
+            let path = history.join(&snapshot.id).join(SNAPSHOT_STATE);
+            let (state, _): (Self, _) = from_file(&path, Some(vars))?;
+            referenced.extend(state.blobs());
+        }
+        for (hash, size) in vars.blobs.list()? {
+            if !referenced.contains(&hash) {
+                log::trace!("deleting blob {}", hash);
+                vars.blobs.remove(&hash)?;
+                report.local_blobs += 1;
+                report.local_bytes += size;
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn sync() -> Result<SyncStatus, failure::Error> {
         let vars = EnvVars::new()?;
+        let transport = transport::open(&vars)?;
+        Self::sync_with(&vars, transport.as_ref())
+    }
+
+    /// Report what `sync` would do, without changing the local state, the
+    /// data directory, the cache of the remote, or the remote itself.
+    fn dry_run() -> Result<SyncPlan, failure::Error> {
+        let vars = EnvVars::new()?;
+        let scratch = vars.xdg_dirs.create_cache_directory("dry-run")?;
+        let transport = transport::open_read_only(&vars, &scratch.join("encrypted"))?;
+        Self::dry_run_with(&vars, transport.as_ref())
+    }
+
+    /// Report what `sync_with` would do with the same arguments.
+    fn dry_run_with(vars: &EnvVars, transport: &dyn Transport) -> Result<SyncPlan, failure::Error> {
+        let _lock = vars.lock()?;
+        let (local, context): (Self, _) = from_file(&vars.crdt, Some(vars))?;
+
+        // Pull into a scratch copy of the cache, so that only what changed
+        // since the last sync needs to be transferred.
+        let cache_dir = vars.xdg_dirs.get_cache_file("replicas");
+        let scratch = vars.xdg_dirs.create_cache_directory("dry-run")?;
+        let replicas = scratch.join("replicas");
+        create_dir_all(&replicas)?;
+        if cache_dir.is_dir() {
+            for entry in read_dir(&cache_dir)? {
+                let path = entry?.path();
+                match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) if !is_temp_file(name) => copy_atomic(&path, &replicas.join(name))?,
+                    _ => (),
+                }
+            }
+        }
+        log::trace!("pulling {}", &replicas.display());
+        transport.pull(&replicas)?;
+        drop_retired(vars, &replicas)?;
+
+        // Our own state on the remote is included, so that what was already
+        // pushed is not reported again.
+        let mut remote = Self::new();
+        let mut remote_context = VersionVector::new();
+        for refused in merge_cached(&replicas, None, &mut remote, &mut remote_context)? {
+            log::warn!("ignoring {}", refused);
+        }
+        for refused in transport.take_refused() {
+            log::warn!("ignoring {}", refused);
+        }
+        if !vars.legacy_merged() {
+            let dir = scratch.join("legacy");
+            create_dir_all(&dir)?;
+            match vars.legacy_state::<Self>(transport, &dir) {
+                Ok(Some(legacy)) => remote.merge(&remote_context, legacy, &VersionVector::new()),
+                Ok(None) => (),
+                Err(e) => log::warn!("ignoring the state left at {}: {}", vars.url, e),
+            }
+        }
+        Self::plan(vars, (&local, &context), (&remote, &remote_context))
+    }
+
+    /// Sync the local state in `vars` with the remote reached via
+    /// `transport`.
+    fn sync_with(vars: &EnvVars, transport: &dyn Transport) -> Result<SyncStatus, failure::Error> {
+        let _lock = vars.lock()?;
 
         log::trace!("loading local serialization");
-        let mut local: Self = load_file(&vars)?;
-
-        let cache_path = vars.remote_cache()?;
-        log::trace!("beginning rsync pull {}", &cache_path.display());
-        // TODO: log the output instead of printing it
-        let result = Command::new("rsync")
-            .arg("--compress")
-            .arg("--verbose")
-            .arg("--ignore-missing-args")
-            .arg(&vars.url)
-            .arg(&cache_path)
-            .status()?;
-        if !result.success() {
-            return Err(format_err!("rsync pull failed: {}", result));
-        }
-        log::trace!("rsync pull {} complete", &cache_path.display());
-
-        // It's possible that the remote side does not yet exist, in which
-        // case we can skip the merging.
-        if cache_path.is_file() {
-            let remote = from_file(&cache_path)?;
-            log::trace!("merging remote");
-            local.merge(remote);
+        let (mut local, mut context): (Self, _) = from_file(&vars.crdt, Some(vars))?;
+        let loaded = context.clone();
+        CrdtPack::pack(vars, &mut local, &mut context)?;
+
+        let replica = vars.replica()?;
+        let cache_dir = vars.remote_cache()?;
+        log::trace!("pulling {}", &cache_dir.display());
+        transport.pull(&cache_dir)?;
+        let retired = drop_retired(vars, &cache_dir)?;
+
+        // Our own state on the remote is never newer than the local one.
+        let mut quarantine = merge_cached(&cache_dir, Some(&replica), &mut local, &mut context)?;
+
+        // The state left by the first releases has no version vector, so it
+        // is merged as if from a replica that has seen nothing: it can add
+        // versions, but never undo a removal.
+        let mut legacy_merged = vars.legacy_merged();
+        if !legacy_merged {
+            let dir = vars.xdg_dirs.create_cache_directory("legacy")?;
+            match vars.legacy_state::<Self>(transport, &dir) {
+                Ok(legacy) => {
+                    if let Some(mut legacy) = legacy {
+                        log::info!("merging the state left at {}", vars.url);
+                        let mut rejected = legacy.take_rejected();
+                        local.merge(&context, legacy, &VersionVector::new());
+                        rejected.extend(local.take_rejected());
+                        for rejected in rejected {
+                            log::warn!("rejected legacy entry: {}", rejected);
+                            quarantine.push(format!("legacy: {}", rejected));
+                        }
+                    }
+                    legacy_merged = true;
+                }
+                Err(e) => {
+                    log::error!("refusing the state left at {}: {}", vars.url, e);
+                    quarantine.push(format!("legacy: {}", e));
+                }
+            }
+        }
+
+        let missing = local
+            .blobs()
+            .into_iter()
+            .filter(|hash| !vars.blobs.contains(hash))
+            .collect::<Vec<Hash>>();
+        if !missing.is_empty() {
+            // Blobs are pulled to the side and checked against their hash, so
+            // that a damaged copy on the remote never makes it into the store.
+            log::trace!("pulling {} missing blobs", missing.len());
+            let incoming = BlobStore::new(&vars.xdg_dirs.create_cache_directory("incoming")?);
+            transport.pull_blobs(&missing, incoming.dir())?;
+            for hash in missing.iter() {
+                let path = incoming.path(hash);
+                if path.is_file() && !vars.blobs.import(hash, &path)? {
+                    log::warn!("refusing damaged blob {}", hash);
+                }
+            }
+        }
+
+        for refused in transport.take_refused() {
+            quarantine.push(refused);
         }
 
         log::trace!("unpacking local copies");
-        CrdtPack::unpack(&vars, &local)?;
+        for failed in CrdtPack::unpack(vars, &local)? {
+            quarantine.push(format!("{}: {}", replica, failed));
+        }
+        vars.report_quarantine(&quarantine)?;
+
+        // Every change to the local state is counted in its version vector, so
+        // a state that has not changed is not worth keeping a snapshot of.
+        if context != loaded {
+            log::trace!("saving history");
+            vars.save_history(Some(&cache_dir))?;
+        }
 
         log::trace!("re-serializing crdts");
-        to_file(&vars.crdt, &local)?;
-        copy(&vars.crdt, &cache_path)?;
-
-        log::trace!("beginning rsync push {}", &cache_path.display());
-        let result = Command::new("rsync")
-            .arg("--compress")
-            .arg("--verbose")
-            .arg("--ignore-missing-args")
-            .arg(&cache_path)
-            .arg(&vars.url)
-            .status()?;
-        if !result.success() {
-            return Err(format_err!("rsync push failed: {}", result));
-        }
-        log::trace!("rsync push {} complete", &cache_path.display());
+        to_file(&vars.crdt, &local, &context)?;
+        if legacy_merged {
+            vars.set_legacy_merged()?;
+        }
+        stage(vars, &cache_dir, &replica, &local, &context)?;
 
-        Ok(())
+        // Push the blobs first so the remote state never refers to blobs the
+        // remote does not have.
+        let blobs = local.blobs().into_iter().collect::<Vec<Hash>>();
+        log::trace!("pushing blobs");
+        transport.push_blobs(&blobs, vars.blobs.dir())?;
+
+        log::trace!("pushing state of replica {}", replica);
+        transport.push(&cache_dir, &replica)?;
+
+        // The retired ids have no files left in the cache, so pushing them
+        // removes their files from the remote.
+        for old in retired.iter() {
+            log::trace!("removing state of retired replica {}", old);
+            transport.push(&cache_dir, old)?;
+        }
+        vars.set_retired(&[])?;
+
+        let conflicts = local.conflicts();
+        for conflict in conflicts.iter() {
+            log::warn!("conflict: {}", conflict);
+        }
+        if conflicts.is_empty() {
+            Ok(SyncStatus::Clean)
+        } else {
+            Ok(SyncStatus::Conflicted)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, write};
+
+    use super::*;
+    use crate::{
+        library::Library,
+        testing::{profile, write_bare, TempDir},
+        transport::LocalDir,
+    };
+
+    fn init(vars: &EnvVars) {
+        to_file(&vars.crdt, &Library::new(), &VersionVector::new()).unwrap();
+    }
+
+    /// Replace `filename` in the data directory, which unpacking left read
+    /// only, with `contents`.
+    fn put(vars: &EnvVars, filename: &str, contents: &str) {
+        let path = vars.data.join(filename);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).unwrap();
+        }
+        if path.is_file() {
+            remove_file(&path).unwrap();
+        }
+        write(&path, contents).unwrap();
+    }
+
+    /// Every file under the data directory, with its contents.
+    fn files(vars: &EnvVars) -> BTreeMap<String, String> {
+        fn walk(dir: &Path, prefix: &str, files: &mut BTreeMap<String, String>) {
+            for entry in read_dir(dir).unwrap() {
+                let entry = entry.unwrap();
+                let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                if entry.file_type().unwrap().is_dir() {
+                    walk(&entry.path(), &format!("{}/", name), files);
+                } else {
+                    files.insert(name, read_to_string(entry.path()).unwrap());
+                }
+            }
+        }
+        let mut files = BTreeMap::new();
+        walk(&vars.data, "", &mut files);
+        files
+    }
+
+    /// Two fresh replicas of a library on a shared local remote.
+    fn pair(root: &TempDir) -> (LocalDir, EnvVars, EnvVars) {
+        let url = root.path().join("remote");
+        let a = profile(root.path(), "a", &url);
+        let b = profile(root.path(), "b", &url);
+        init(&a);
+        init(&b);
+        (LocalDir::new(&url), a, b)
+    }
+
+    /// Every file under `dir` and its subdirectories.
+    fn all_files(dir: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for entry in read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                paths.extend(all_files(&path));
+            } else {
+                paths.push(path);
+            }
+        }
+        paths
+    }
+
+    #[test]
+    fn refuses_damaged_blobs_from_the_remote() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "book.txt", "contents of a book");
+        Library::sync_with(&a, &remote).unwrap();
+        for path in all_files(&root.path().join("remote.blobs")) {
+            write(path, "damaged").unwrap();
+        }
+
+        Library::sync_with(&b, &remote).unwrap();
+        assert!(files(&b).is_empty());
+        assert!(b.blobs.list().unwrap().is_empty());
+
+        // A damaged copy in the local store is dropped rather than unpacked.
+        put(&b, "other.txt", "other contents");
+        Library::sync_with(&b, &remote).unwrap();
+        for path in all_files(b.blobs.dir()) {
+            write(path, "damaged").unwrap();
+        }
+        remove_file(b.data.join("other.txt")).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert!(!b.data.join("other.txt").exists());
+        assert!(b.blobs.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_both_sides_of_a_conflict() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "notes.txt", "notes from a");
+        put(&b, "notes.txt", "notes from b");
+        assert_eq!(Library::sync_with(&a, &remote).unwrap(), SyncStatus::Clean);
+        assert_eq!(
+            Library::sync_with(&b, &remote).unwrap(),
+            SyncStatus::Conflicted
+        );
+        assert_eq!(
+            Library::sync_with(&a, &remote).unwrap(),
+            SyncStatus::Conflicted
+        );
+
+        // Each side keeps its own version in place, and a copy of the other.
+        for (vars, own, other) in [
+            (&a, "notes from a", "notes from b"),
+            (&b, "notes from b", "notes from a"),
+        ] {
+            let files = files(vars);
+            assert_eq!(files.len(), 2);
+            assert_eq!(files["notes.txt"], own);
+            let (copy, contents) = files.iter().find(|(name, _)| *name != "notes.txt").unwrap();
+            assert!(
+                copy.starts_with("notes.conflict-") && copy.ends_with(".txt"),
+                "{}",
+                copy
+            );
+            assert_eq!(contents, other);
+        }
+
+        // Removing a copy settles the conflict in favor of the other version.
+        let copy = files(&a)
+            .into_keys()
+            .find(|name| name != "notes.txt")
+            .unwrap();
+        Library::remove_with(&a, &[copy]).unwrap();
+        assert_eq!(Library::sync_with(&a, &remote).unwrap(), SyncStatus::Clean);
+        assert_eq!(Library::sync_with(&b, &remote).unwrap(), SyncStatus::Clean);
+        assert_eq!(files(&b)["notes.txt"], "notes from a");
+        assert_eq!(files(&a), files(&b));
+    }
+
+    #[test]
+    fn removals_reach_other_replicas() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "book.txt", "contents of a book");
+        put(&a, "notes.txt", "some notes");
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert_eq!(files(&b).len(), 2);
+
+        Library::remove_with(&b, &["book.txt".to_string()]).unwrap();
+        assert!(!b.data.join("book.txt").exists());
+        assert!(Library::remove_with(&b, &["book.txt".to_string()]).is_err());
+        Library::sync_with(&b, &remote).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        assert_eq!(files(&a).keys().collect::<Vec<_>>(), ["notes.txt"]);
+        assert_eq!(files(&a), files(&b));
+
+        // A file deleted without recording its removal is restored instead.
+        remove_file(a.data.join("notes.txt")).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        assert_eq!(files(&a), files(&b));
+    }
+
+    #[test]
+    fn gc_purges_what_every_replica_has_seen() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "book.txt", "contents of a book");
+        put(&a, "notes.txt", "some notes");
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+
+        // Once both replicas have seen a removal, it is purged along with the
+        // blobs only it referred to.
+        Library::remove_with(&b, &["book.txt".to_string()]).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        assert_eq!(files(&a), files(&b));
+
+        let report = Library::gc_with(&a, &remote).unwrap();
+        assert!(report.purged >= 1, "{:?}", report);
+        assert!(report.remote_blobs >= 1, "{:?}", report);
+        assert_eq!(Library::sync_with(&b, &remote).unwrap(), SyncStatus::Clean);
+        assert_eq!(Library::gc_with(&b, &remote).unwrap().purged, 0);
+        assert_eq!(files(&a), files(&b));
+
+        // Nothing the remaining files need was collected.
+        let c = profile(root.path(), "c", &root.path().join("remote"));
+        init(&c);
+        assert_eq!(Library::sync_with(&c, &remote).unwrap(), SyncStatus::Clean);
+        assert_eq!(files(&a), files(&c));
+    }
+
+    #[test]
+    fn unpacks_nested_files_and_removes_empty_dirs() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "author/series/one.txt", "first book");
+        put(&a, "author/two.txt", "second book");
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert_eq!(files(&a), files(&b));
+
+        Library::remove_with(&a, &["author/series/one.txt".to_string()]).unwrap();
+        assert!(!a.data.join("author/series").exists());
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert!(!b.data.join("author/series").exists());
+        assert_eq!(files(&b).keys().collect::<Vec<_>>(), ["author/two.txt"]);
+
+        Library::remove_with(&a, &["author/two.txt".to_string()]).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert!(!b.data.join("author").exists());
+        assert!(b.data.is_dir());
+    }
+
+    #[test]
+    fn reports_files_that_clash_with_directories() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "notes", "a file");
+        put(&b, "notes/x.txt", "a file in a directory");
+        put(&b, "book.txt", "contents of a book");
+
+        // Each side keeps its own, reports the other, and carries on.
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        assert_eq!(read_to_string(a.data.join("notes")).unwrap(), "a file");
+        assert_eq!(
+            read_to_string(a.data.join("book.txt")).unwrap(),
+            "contents of a book"
+        );
+        assert!(b.data.join("notes").is_dir());
+        for vars in [&a, &b] {
+            let quarantine = read_to_string(vars.xdg_dirs.get_data_file("quarantine")).unwrap();
+            assert!(quarantine.contains("notes"), "{}", quarantine);
+        }
+        let quarantine = read_to_string(a.xdg_dirs.get_data_file("quarantine")).unwrap();
+        assert!(quarantine.contains("notes/x.txt"), "{}", quarantine);
+
+        // Once one side is removed, the other is unpacked everywhere.
+        Library::remove_with(&a, &["notes".to_string()]).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        assert_eq!(files(&a), files(&b));
+        assert!(!a.xdg_dirs.get_data_file("quarantine").exists());
+        assert!(!b.xdg_dirs.get_data_file("quarantine").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn never_writes_through_symlinked_directories() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        let outside = root.path().join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, b.data.join("author")).unwrap();
+
+        put(&a, "author/book.txt", "contents of a book");
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert!(!outside.join("book.txt").exists());
+        let quarantine = read_to_string(b.xdg_dirs.get_data_file("quarantine")).unwrap();
+        assert!(quarantine.contains("author/book.txt"), "{}", quarantine);
+
+        // Nor removes anything through them.
+        write(outside.join("book.txt"), "kept").unwrap();
+        Library::remove_with(&a, &["author/book.txt".to_string()]).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert_eq!(read_to_string(outside.join("book.txt")).unwrap(), "kept");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restores_metadata_on_other_replicas() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        let modified = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        put(&a, "run.sh", "#!/bin/sh");
+        put(&a, "book.txt", "contents of a book");
+        let script = File::options()
+            .write(true)
+            .open(a.data.join("run.sh"))
+            .unwrap();
+        script.set_modified(modified).unwrap();
+        script
+            .set_permissions(std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        let script = b.data.join("run.sh").metadata().unwrap();
+        assert_eq!(script.modified().unwrap(), modified);
+        assert_eq!(script.permissions().mode() & 0o777, 0o555);
+        let book = b.data.join("book.txt").metadata().unwrap();
+        assert_eq!(
+            book.modified().unwrap(),
+            a.data
+                .join("book.txt")
+                .metadata()
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+        assert_eq!(book.permissions().mode() & 0o777, 0o444);
+    }
+
+    #[test]
+    fn quarantines_what_cannot_be_decrypted() {
+        let root = TempDir::new();
+        let (_, a, b) = pair(&root);
+        write(a.xdg_dirs.place_config_file("key").unwrap(), "secret").unwrap();
+        write(b.xdg_dirs.place_config_file("key").unwrap(), "wrong").unwrap();
+        put(&a, "book.txt", "contents of a book");
+
+        Library::sync_with(&a, &*transport::open(&a).unwrap()).unwrap();
+        Library::sync_with(&b, &*transport::open(&b).unwrap()).unwrap();
+        assert!(files(&b).is_empty());
+        let quarantine = read_to_string(b.xdg_dirs.get_data_file("quarantine")).unwrap();
+        assert!(
+            quarantine.contains(&format!("{}: cannot decrypt", a.replica().unwrap())),
+            "{}",
+            quarantine
+        );
+
+        // The state that b sealed with the wrong key is refused in turn, until
+        // it is replaced.
+        write(b.xdg_dirs.place_config_file("key").unwrap(), "secret").unwrap();
+        Library::sync_with(&b, &*transport::open(&b).unwrap()).unwrap();
+        assert_eq!(files(&a), files(&b));
+        Library::sync_with(&a, &*transport::open(&a).unwrap()).unwrap();
+        Library::sync_with(&b, &*transport::open(&b).unwrap()).unwrap();
+        assert!(!a.xdg_dirs.get_data_file("quarantine").exists());
+        assert!(!b.xdg_dirs.get_data_file("quarantine").exists());
+    }
+
+    #[test]
+    fn envelope_round_trips() {
+        let root = TempDir::new();
+        let path = root.path().join("state.cbor");
+        let mut context = VersionVector::new();
+        context.increment("a");
+        to_file(&path, &Library::new(), &context).unwrap();
+
+        let (_, loaded): (Library, _) = from_file(&path, None).unwrap();
+        assert_eq!(loaded, context);
+    }
+
+    #[test]
+    fn envelope_refuses_corruption() {
+        let root = TempDir::new();
+        let vars = profile(root.path(), "a", &root.path().join("remote"));
+        put(&vars, "book.txt", "contents of a book");
+        let mut library = Library::new();
+        let mut context = VersionVector::new();
+        Library::pack(&vars, &mut library, &mut context).unwrap();
+        let path = root.path().join("state.cbor");
+        to_file(&path, &library, &context).unwrap();
+        let state = read(&path).unwrap();
+
+        let damaged = root.path().join("damaged.cbor");
+        for i in 0..state.len() {
+            let mut flipped = state.clone();
+            flipped[i] ^= 0x01;
+            write(&damaged, &flipped).unwrap();
+            assert!(from_file::<Library>(&damaged, None).is_err(), "byte {}", i);
+        }
+        for len in 0..state.len() {
+            write(&damaged, &state[..len]).unwrap();
+            assert!(
+                from_file::<Library>(&damaged, None).is_err(),
+                "{} bytes",
+                len
+            );
+        }
+        write(&damaged, [state.as_slice(), b"\0"].concat()).unwrap();
+        assert!(from_file::<Library>(&damaged, None).is_err());
+    }
+
+    #[test]
+    fn envelope_refuses_newer_schema() {
+        let root = TempDir::new();
+        let path = root.path().join("state.cbor");
+        to_file(&path, &Library::new(), &VersionVector::new()).unwrap();
+        let envelope = read_envelope(&path, false).unwrap();
+
+        let mut payload = Vec::new();
+        into_writer(&envelope.context, &mut payload).unwrap();
+        payload.extend_from_slice(&envelope.payload);
+        let mut state = STATE_MAGIC.to_vec();
+        state.extend_from_slice(&STATE_FORMAT.to_be_bytes());
+        state.extend_from_slice(&(Library::SCHEMA_VERSION + 1).to_be_bytes());
+        state.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        state.extend_from_slice(Hash::of(&payload).as_bytes());
+        state.extend_from_slice(&payload);
+        write(&path, state).unwrap();
+
+        let e = from_file::<Library>(&path, None).err().unwrap();
+        assert!(e.to_string().contains("newer"), "{}", e);
+    }
+
+    #[test]
+    fn quarantines_damaged_replica_state() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        let url = root.path().join("remote");
+        put(&a, "book.txt", "contents of a book");
+        put(&b, "notes.txt", "some notes");
+        Library::sync_with(&a, &remote).unwrap();
+
+        let pushed = url
+            .with_extension("replicas")
+            .join(format!("{}.cbor", a.replica().unwrap()));
+        let mut state = read(&pushed).unwrap();
+        let last = state.len() - 1;
+        state[last] ^= 0x01;
+        write(&pushed, state).unwrap();
+
+        assert_eq!(Library::sync_with(&b, &remote).unwrap(), SyncStatus::Clean);
+        assert_eq!(files(&b).keys().collect::<Vec<_>>(), ["notes.txt"]);
+        let quarantine = read_to_string(b.xdg_dirs.get_data_file("quarantine")).unwrap();
+        assert!(quarantine.contains("checksum mismatch"), "{}", quarantine);
+
+        // The next push from the damaged replica replaces its state.
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert_eq!(files(&a), files(&b));
+        assert!(b.xdg_dirs.find_data_file("quarantine").is_none());
+    }
+
+    #[test]
+    fn reports_status_without_syncing() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "book.txt", "contents of a book");
+        put(&a, "notes.txt", "some notes");
+        put(&a, "other.txt", "other contents");
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        assert!(Library::status_with(&b).unwrap().is_clean());
+
+        put(&b, "new.txt", "a new file");
+        remove_file(b.data.join("book.txt")).unwrap();
+        put(&b, "notes.txt", "edited notes");
+        put(&a, "remote.txt", "from a");
+        Library::remove_with(&a, &["other.txt".to_string()]).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        // As if b's last sync was cut short after pulling.
+        remote.pull(&b.remote_cache().unwrap()).unwrap();
+
+        let status = Library::status_with(&b).unwrap();
+        assert_eq!(status.new, ["new.txt"]);
+        assert_eq!(status.remote, ["remote.txt"]);
+        assert_eq!(status.missing, ["book.txt"]);
+        assert_eq!(status.modified, ["notes.txt"]);
+        assert!(status.conflicted.is_empty());
+        assert!(!status.is_clean());
+        assert!(b.data.join("other.txt").is_file());
+        assert!(!b.data.join("remote.txt").exists());
+
+        // Concurrent edits show up as conflicts once the remote is pulled.
+        put(&a, "notes.txt", "notes from a");
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+        remote.pull(&b.remote_cache().unwrap()).unwrap();
+        let status = Library::status_with(&b).unwrap();
+        assert_eq!(status.conflicted.len(), 1, "{:?}", status);
+        assert!(status.conflicted[0].contains("notes.txt"), "{:?}", status);
+    }
+
+    /// Every file under `dir` with its contents, or nothing if it is missing.
+    fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        if !dir.is_dir() {
+            return BTreeMap::new();
+        }
+        all_files(dir)
+            .into_iter()
+            .map(|path| {
+                let contents = read(&path).unwrap();
+                (path, contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dry_run_reports_what_sync_does() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "book.txt", "contents of a book");
+        put(&a, "notes.txt", "some notes");
+        Library::sync_with(&a, &remote).unwrap();
+        Library::sync_with(&b, &remote).unwrap();
+
+        put(&a, "notes.txt", "edited notes");
+        put(&a, "remote.txt", "from a");
+        Library::remove_with(&a, &["book.txt".to_string()]).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        put(&b, "new.txt", "a new file");
+
+        let state = read(&b.crdt).unwrap();
+        let data = files(&b);
+        let cache = snapshot(&b.remote_cache().unwrap());
+        let on_remote = snapshot(&root.path().join("remote"));
+        let plan = Library::dry_run_with(&b, &remote).unwrap();
+        assert_eq!(read(&b.crdt).unwrap(), state);
+        assert_eq!(files(&b), data);
+        assert_eq!(snapshot(&b.remote_cache().unwrap()), cache);
+        assert_eq!(snapshot(&root.path().join("remote")), on_remote);
+
+        assert_eq!(plan.add, ["new.txt"]);
+        assert_eq!(plan.unpack, ["notes.txt", "remote.txt"]);
+        assert_eq!(plan.remove, ["book.txt"]);
+        assert_eq!(plan.push, ["new.txt"]);
+        assert!(plan.conflicts.is_empty());
+
+        // The data directory changes exactly as planned.
+        assert_eq!(Library::sync_with(&b, &remote).unwrap(), SyncStatus::Clean);
+        let after = files(&b);
+        let removed: Vec<_> = data
+            .keys()
+            .filter(|name| !after.contains_key(*name))
+            .collect();
+        let written: Vec<_> = after
+            .iter()
+            .filter(|(name, contents)| data.get(*name) != Some(*contents))
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(removed, plan.remove.iter().collect::<Vec<_>>());
+        assert_eq!(written, plan.unpack.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dry_run_leaves_a_new_encrypted_remote_alone() {
+        let root = TempDir::new();
+        let (remote, a, b) = pair(&root);
+        put(&a, "book.txt", "contents of a book");
+        Library::sync_with(&a, &remote).unwrap();
+        write(b.xdg_dirs.place_config_file("key").unwrap(), "secret").unwrap();
+        put(&b, "notes.txt", "some notes");
+
+        // The plain objects that a sync would move over are reported, but
+        // nothing is sealed yet.
+        let on_remote = snapshot(&root.path().join("remote"));
+        let scratch = root.path().join("scratch");
+        let transport = transport::open_read_only(&b, &scratch).unwrap();
+        let plan = Library::dry_run_with(&b, transport.as_ref()).unwrap();
+        assert_eq!(plan.add, ["notes.txt"]);
+        assert_eq!(plan.unpack, ["book.txt"]);
+        assert_eq!(snapshot(&root.path().join("remote")), on_remote);
+        assert!(!root.path().join("remote.sealed").exists());
+    }
+
+    #[test]
+    fn restores_a_snapshot() {
+        let root = TempDir::new();
+        let (remote, a, _) = pair(&root);
+        put(&a, "book.txt", "first book");
+        put(&a, "notes.txt", "first notes");
+        put(&a, "old.txt", "old contents");
+        Library::sync_with(&a, &remote).unwrap();
+        Library::remove_with(&a, &["old.txt".to_string()]).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+
+        put(&a, "book.txt", "second book");
+        put(&a, "notes.txt", "second notes");
+        put(&a, "old.txt", "added again");
+        put(&a, "extra.txt", "extra contents");
+        let before = snapshots(&a).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        let snapshot = snapshots(&a)
+            .unwrap()
+            .into_iter()
+            .find(|snapshot| before.iter().all(|other| other.id != snapshot.id))
+            .unwrap();
+
+        // Edits made since the last sync are never rolled back.
+        put(&a, "book.txt", "edited book");
+        put(&a, "old.txt", "edited again");
+        let replica = a.replica().unwrap();
+        assert!(Library::restore_with(&a, "missing", true).is_err());
+        Library::restore_with(&a, &snapshot.id, true).unwrap();
+        let files = files(&a);
+        assert_eq!(files.len(), 3, "{:?}", files);
+        assert_eq!(files["book.txt"], "edited book");
+        assert_eq!(files["notes.txt"], "first notes");
+        assert_eq!(files["old.txt"], "edited again");
+        assert_ne!(a.replica().unwrap(), replica);
+
+        let (restored, _): (Library, _) = from_file(&a.crdt, Some(&a)).unwrap();
+        let live = |filename| restored.provenance(filename).iter().any(|v| v.live);
+        assert!(live("notes.txt"));
+        assert!(!live("old.txt"));
+        assert!(!live("extra.txt"));
+    }
+
+    #[test]
+    fn restores_only_the_state_when_asked() {
+        let root = TempDir::new();
+        let (remote, a, _) = pair(&root);
+        put(&a, "book.txt", "first book");
+        Library::sync_with(&a, &remote).unwrap();
+        put(&a, "book.txt", "second book");
+        let before = snapshots(&a).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        let snapshot = snapshots(&a)
+            .unwrap()
+            .into_iter()
+            .find(|snapshot| before.iter().all(|other| other.id != snapshot.id))
+            .unwrap();
+
+        Library::restore_with(&a, &snapshot.id, false).unwrap();
+        assert_eq!(files(&a)["book.txt"], "second book");
+        assert_eq!(Library::status_with(&a).unwrap().modified, ["book.txt"]);
+    }
+
+    #[test]
+    fn generates_random_ids() {
+        let ids = (0..100)
+            .map(|_| unique_id().unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), 100);
+        for id in &ids {
+            assert_eq!(id.len(), 32, "{}", id);
+            assert!(id.bytes().all(|b| b.is_ascii_hexdigit()), "{}", id);
+        }
+
+        let root = TempDir::new();
+        let (_, a, b) = pair(&root);
+        assert_ne!(a.replica().unwrap(), b.replica().unwrap());
+        assert_eq!(a.replica().unwrap(), a.replica().unwrap());
+    }
+
+    #[test]
+    fn refuses_a_profile_that_is_in_use() {
+        let root = TempDir::new();
+        let (remote, a, _) = pair(&root);
+
+        let held = a.lock().unwrap();
+        let e = a.lock().err().unwrap();
+        assert!(e.to_string().contains("held by another"), "{}", e);
+        assert!(Library::sync_with(&a, &remote).is_err());
+        assert!(Library::gc_with(&a, &remote).is_err());
+        drop(held);
+        assert_eq!(Library::sync_with(&a, &remote).unwrap(), SyncStatus::Clean);
+    }
+
+    #[test]
+    fn waits_for_the_lock_until_the_timeout() {
+        let root = TempDir::new();
+        let (_, mut a, _) = pair(&root);
+        a.lock_timeout = Some(Duration::from_millis(300));
+
+        let held = a.lock().unwrap();
+        let start = Instant::now();
+        assert!(a.lock().is_err());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // A lock released while waiting is taken.
+        a.lock_timeout = Some(Duration::from_secs(10));
+        let release = std::thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            drop(held);
+        });
+        let start = Instant::now();
+        let _lock = a.lock().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn merges_legacy_remote_once() {
+        let root = TempDir::new();
+        let url = root.path().join("remote");
+        write_bare(&url, &[("old.txt", b"from the old remote")]);
+        let remote = LocalDir::new(&url);
+        let a = profile(root.path(), "a", &url);
+        init(&a);
+
+        assert_eq!(Library::sync_with(&a, &remote).unwrap(), SyncStatus::Clean);
+        assert_eq!(
+            read(a.data.join("old.txt")).unwrap(),
+            b"from the old remote"
+        );
+        assert!(a.legacy_merged());
+
+        // A removal is not undone by the legacy state, which is never merged
+        // again.
+        Library::remove_with(&a, &["old.txt".to_string()]).unwrap();
+        Library::sync_with(&a, &remote).unwrap();
+        let path = a.data.join("old.txt");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn counts_replicas_in_snapshots() {
+        let root = TempDir::new();
+        let a = profile(root.path(), "a", &root.path().join("remote"));
+        init(&a);
+
+        let cache = root.path().join("replicas");
+        create_dir_all(&cache).unwrap();
+        for name in ["b.cbor", "b.1.delta", "b.2.delta", "c.cbor"] {
+            copy_atomic(&a.crdt, &cache.join(name)).unwrap();
+        }
+        a.save_history(Some(&cache)).unwrap();
+        a.save_history(None).unwrap();
+
+        let snapshots = snapshots(&a).unwrap();
+        let mut counts = snapshots.iter().map(|s| s.replicas).collect::<Vec<_>>();
+        counts.sort();
+        assert_eq!(counts, [0, 2]);
+    }
+
+    #[test]
+    fn refuses_damaged_legacy_remote() {
+        let root = TempDir::new();
+        let url = root.path().join("remote");
+        std::fs::write(&url, b"\xa1\x63set").unwrap();
+        let remote = LocalDir::new(&url);
+        let a = profile(root.path(), "a", &url);
+        init(&a);
+
+        assert_eq!(Library::sync_with(&a, &remote).unwrap(), SyncStatus::Clean);
+        assert!(!a.legacy_merged());
+        let quarantine = read_to_string(a.xdg_dirs.get_data_file("quarantine")).unwrap();
+        assert!(quarantine.starts_with("legacy: "));
     }
 }
@@ -0,0 +1,43 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::process::ExitCode;
+
+use magpie::library::Library;
+use magpie::{CrdtPack, GcReport};
+
+fn print_report(report: &GcReport) {
+    println!(
+        "state    {} removed entries, {} bytes",
+        report.purged, report.state_bytes
+    );
+    println!(
+        "local    {} blobs, {} bytes",
+        report.local_blobs, report.local_bytes
+    );
+    println!(
+        "remote   {} blobs, {} bytes",
+        report.remote_blobs, report.remote_bytes
+    );
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    if let Some(arg) = std::env::args().nth(1) {
+        log::error!("unexpected argument: {}", arg);
+        return ExitCode::FAILURE;
+    }
+
+    match Library::gc() {
+        Ok(report) => {
+            print_report(&report);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,33 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::process::ExitCode;
+
+use magpie::library::Library;
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let filenames = std::env::args().skip(1).collect::<Vec<String>>();
+    match Library::history(&filenames) {
+        Ok(history) => {
+            for version in history.iter() {
+                println!(
+                    "{:>6} {} {} {} {:<7} {}",
+                    version.clock,
+                    version.tag,
+                    version.replica.as_deref().unwrap_or("unknown"),
+                    version.hash,
+                    if version.live { "live" } else { "removed" },
+                    version.filename
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,19 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::process::ExitCode;
+
+use magpie::library::Library;
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let filenames = std::env::args().skip(1).collect::<Vec<String>>();
+    if let Err(e) = Library::remove(&filenames) {
+        log::error!("{}", e);
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use magpie::library::Library;
+use magpie::{snapshots, CrdtPack, EnvVars};
+
+/// Format `time` as an RFC 3339 timestamp in UTC.
+fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Convert days since the epoch to a civil date, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+fn list() -> Result<(), failure::Error> {
+    let vars = EnvVars::new()?;
+    for snapshot in snapshots(&vars)? {
+        println!(
+            "{} {} {} replicas",
+            snapshot.id,
+            format_time(snapshot.time),
+            snapshot.replicas
+        );
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let mut data = false;
+    let mut ids = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--data" => data = true,
+            _ if arg.starts_with("--") => {
+                log::error!("unexpected argument: {}", arg);
+                return ExitCode::FAILURE;
+            }
+            _ => ids.push(arg),
+        }
+    }
+
+    let result = match ids.as_slice() {
+        [] if !data => list(),
+        [id] => Library::restore(id, data),
+        _ => {
+            log::error!("usage: magpie-library-restore [--data <snapshot>]");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = result {
+        log::error!("{}", e);
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,96 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fmt::Write;
+use std::process::ExitCode;
+
+use magpie::library::{Library, Status};
+use magpie::EXIT_UNSETTLED;
+
+/// Quote `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn sections(status: &Status) -> [(&'static str, &Vec<String>); 5] {
+    [
+        ("new", &status.new),
+        ("remote", &status.remote),
+        ("missing", &status.missing),
+        ("modified", &status.modified),
+        ("conflicted", &status.conflicted),
+    ]
+}
+
+fn print_json(status: &Status) {
+    let fields = sections(status)
+        .iter()
+        .map(|(name, files)| {
+            let files = files
+                .iter()
+                .map(|f| json_string(f))
+                .collect::<Vec<String>>();
+            format!("{}:[{}]", json_string(name), files.join(","))
+        })
+        .collect::<Vec<String>>();
+    println!("{{{}}}", fields.join(","));
+}
+
+fn print_text(status: &Status) {
+    for (name, files) in sections(status).iter() {
+        for file in files.iter() {
+            println!("{:<10} {}", name, file);
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let mut json = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ => {
+                log::error!("unexpected argument: {}", arg);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    match Library::status() {
+        Ok(status) => {
+            if json {
+                print_json(&status);
+            } else {
+                print_text(&status);
+            }
+            if status.is_clean() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(EXIT_UNSETTLED)
+            }
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -5,15 +5,56 @@
 use std::process::ExitCode;
 
 use magpie::library::Library;
-use magpie::CrdtPack;
+use magpie::{CrdtPack, SyncPlan, SyncStatus, EXIT_UNSETTLED};
+
+fn print_plan(plan: &SyncPlan) {
+    let actions = [
+        ("add", &plan.add),
+        ("unpack", &plan.unpack),
+        ("remove", &plan.remove),
+        ("push", &plan.push),
+        ("conflict", &plan.conflicts),
+    ];
+    for (action, names) in actions.iter() {
+        for name in names.iter() {
+            println!("{:<8} {}", action, name);
+        }
+    }
+}
 
 fn main() -> ExitCode {
     env_logger::init();
 
-    if let Err(e) = Library::sync() {
-        log::error!("{}", e);
-        ExitCode::FAILURE
+    let mut dry_run = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            _ => {
+                log::error!("unexpected argument: {}", arg);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let status = if dry_run {
+        Library::dry_run().map(|plan| {
+            print_plan(&plan);
+            if plan.conflicts.is_empty() {
+                SyncStatus::Clean
+            } else {
+                SyncStatus::Conflicted
+            }
+        })
     } else {
-        ExitCode::SUCCESS
+        Library::sync()
+    };
+
+    match status {
+        Ok(SyncStatus::Clean) => ExitCode::SUCCESS,
+        Ok(SyncStatus::Conflicted) => ExitCode::from(EXIT_UNSETTLED),
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
     }
 }
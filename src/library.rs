@@ -4,83 +4,1590 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    fs::{metadata, read_dir, set_permissions, File},
-    io::{Read, Write},
+    fs::{
+        create_dir_all, read_dir, remove_dir, remove_file, set_permissions, symlink_metadata, File,
+        Metadata, Permissions,
+    },
+    io::{ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use ciborium::value::Value;
 use failure::{format_err, Error};
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
-use crate::{CrdtPack, EnvVars};
+use crate::{
+    clock::VersionVector,
+    from_file,
+    hash::{Hash, Hasher},
+    is_temp_file, merge_cached, to_file, unique_id, write_atomic, CrdtPack, EnvVars, SyncPlan,
+};
 
-#[derive(Deserialize, Serialize)]
+/// An observed-remove set of files, keyed by their path relative to the data
+/// directory, with `/` separating directories on every platform.
+///
+/// Only the hash and metadata of each file are kept here; the contents live
+/// in the blob store.
+///
+/// Every time a file is added it is given a unique tag; removing a file
+/// tombstones all of the tags that were observed for that filename. A file is
+/// present as long as it has at least one tag that has not been tombstoned, so
+/// a removal on one replica wins over the adds it has seen, but not over
+/// concurrent adds it has not.
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Library {
-    set: HashMap<String, ByteBuf>,
+    set: HashMap<String, HashMap<String, Version>>,
+    /// The tags of every removed version, and where each was removed, unless
+    /// that was before removals were counted.
+    removed: HashMap<String, Option<Removal>>,
+
+    /// Entries refused by `merge` since the last call to `take_rejected`.
+    #[serde(skip)]
+    rejected: Vec<String>,
+}
+
+/// One version of a file, as it was when it was added.
+#[derive(Clone, Deserialize, Serialize)]
+struct Version {
+    /// Hash of the whole file, which identifies this version.
+    hash: Hash,
+    /// The blobs holding the contents of the file, in order.
+    chunks: Vec<Hash>,
+    modified: SystemTime,
+    executable: bool,
+    /// Where this version was added, unless that was before replicas recorded
+    /// themselves.
+    origin: Option<Origin>,
+}
+
+/// The replica that added a version, and when.
+#[derive(Clone, Deserialize, Serialize)]
+struct Origin {
+    replica: String,
+    /// Lamport timestamp of the addition: greater than that of every version
+    /// the adding replica had seen.
+    clock: u64,
+    /// Position of the addition among the changes made on `replica`, or 0 if
+    /// it was added before those were counted.
+    seq: u64,
+}
+
+/// The replica that removed a version, and the position of the removal
+/// among the changes made there.
+#[derive(Clone, Deserialize, Serialize)]
+struct Removal {
+    replica: String,
+    seq: u64,
+}
+
+/// How the data directory differs from the library, and the library from the
+/// last states fetched from other replicas. Every list is sorted.
+#[derive(Debug, Default)]
+pub struct Status {
+    /// Files in the data directory that have not been added yet.
+    pub new: Vec<String>,
+    /// Files that other replicas have added, but this one has not seen yet.
+    pub remote: Vec<String>,
+    /// Files in the library that are gone from the data directory.
+    pub missing: Vec<String>,
+    /// Files whose contents changed since they were added.
+    pub modified: Vec<String>,
+    /// Files with divergent versions, once the other replicas are merged in.
+    pub conflicted: Vec<String>,
+}
+
+impl Status {
+    pub fn is_clean(&self) -> bool {
+        self.new.is_empty()
+            && self.remote.is_empty()
+            && self.missing.is_empty()
+            && self.modified.is_empty()
+            && self.conflicted.is_empty()
+    }
+}
+
+/// Where one version of a file in the library came from.
+#[derive(Debug)]
+pub struct Provenance {
+    pub filename: String,
+    /// The tag that identifies this version.
+    pub tag: String,
+    pub hash: Hash,
+    /// The replica that added this version, if it was recorded.
+    pub replica: Option<String>,
+    /// Lamport timestamp of the addition, or 0 if it was not recorded.
+    pub clock: u64,
+    /// Whether the version is still present, rather than removed or replaced.
+    pub live: bool,
+}
+
+impl Version {
+    fn new(
+        hash: Hash,
+        chunks: Vec<Hash>,
+        metadata: &Metadata,
+        origin: Option<Origin>,
+    ) -> Result<Version, Error> {
+        // A time before the epoch cannot be serialized, and would keep the
+        // whole library from being saved.
+        let mut modified = metadata.modified()?;
+        if modified < UNIX_EPOCH {
+            warn!("recording a modification time before 1970 as 1970");
+            modified = UNIX_EPOCH;
+        }
+
+        Ok(Version {
+            hash,
+            chunks,
+            modified,
+            executable: is_executable(metadata),
+            origin,
+        })
+    }
+
+    /// The replica that added this version and the position of the addition
+    /// among its changes, if both were recorded.
+    fn dot(&self) -> Option<(&str, u64)> {
+        match &self.origin {
+            Some(origin) if origin.seq > 0 => Some((&origin.replica, origin.seq)),
+            _ => None,
+        }
+    }
+
+    /// Whether `context` has seen this version being added.
+    fn seen_by(&self, context: &VersionVector) -> bool {
+        self.dot()
+            .is_some_and(|(replica, seq)| context.covers(replica, seq))
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &Metadata) -> bool {
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Make `perms` read-only, and executable by everyone if `executable` is set.
+#[cfg(unix)]
+fn set_mode(perms: &mut Permissions, executable: bool) {
+    perms.set_mode(if executable { 0o555 } else { 0o444 });
+}
+
+#[cfg(not(unix))]
+fn set_mode(perms: &mut Permissions, _executable: bool) {
+    perms.set_readonly(true);
+}
+
+/// Names that cannot be used for a file on some platforms, regardless of
+/// extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Check that `filename` is a relative path that stays inside of the data
+/// directory and can be created on every platform.
+fn validate_name(filename: &str) -> Result<(), &'static str> {
+    if filename.is_empty() {
+        return Err("empty name");
+    }
+    if filename.contains('\0') {
+        return Err("contains a NUL byte");
+    }
+    if filename.contains('\\') {
+        return Err("contains a backslash");
+    }
+    if filename.starts_with('/') {
+        return Err("absolute path");
+    }
+    for component in filename.split('/') {
+        match component {
+            "" => return Err("empty path component"),
+            "." | ".." => return Err("relative path component"),
+            _ => (),
+        }
+        let stem = component.split('.').next().unwrap_or_default();
+        if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+            return Err("reserved name");
+        }
+        let mut parts = Path::new(component).components();
+        if !matches!(
+            (parts.next(), parts.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err("not a plain file name");
+        }
+    }
+    Ok(())
+}
+
+/// Check that `tag` is safe to embed in the name of a conflict copy.
+fn validate_tag(tag: &str) -> Result<(), &'static str> {
+    if !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        Ok(())
+    } else {
+        Err("invalid tag")
+    }
+}
+
+/// Check that `replica` is safe to show alongside the versions it added.
+fn validate_replica(replica: &str) -> Result<(), &'static str> {
+    validate_tag(replica).map_err(|_| "invalid replica")
+}
+
+/// Check every version of `filename` received from another replica.
+fn validate_entry(filename: &str, tags: &HashMap<String, Version>) -> Result<(), &'static str> {
+    validate_name(filename)?;
+    for (tag, version) in tags.iter() {
+        validate_tag(tag)?;
+        if let Some(origin) = &version.origin {
+            validate_replica(&origin.replica)?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of the copy that holds the version of `filename` added as `tag` while
+/// that file is in conflict, e.g. `book.conflict-<tag>.pdf`.
+fn conflict_name(filename: &str, tag: &str) -> String {
+    let base = filename.rfind('/').map_or(0, |i| i + 1);
+    match filename[base..].rfind('.') {
+        Some(i) if i > 0 => {
+            let (stem, ext) = filename.split_at(base + i);
+            format!("{}.conflict-{}{}", stem, tag, ext)
+        }
+        _ => format!("{}.conflict-{}", filename, tag),
+    }
+}
+
+/// Resolve `filename` to its path in the data directory `data`, or `None` if
+/// it is not a valid name.
+fn key_path(data: &Path, filename: &str) -> Option<PathBuf> {
+    validate_name(filename).ok()?;
+    Some(data.join(filename.split('/').collect::<PathBuf>()))
+}
+
+/// Collect every regular file under `dir`, named relative to the data
+/// directory, where `dir` is found at `prefix`.
+fn walk(dir: &Path, prefix: &str, files: &mut HashSet<String>) -> Result<(), Error> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|e| format_err!("invalid file: {}", e.to_string_lossy()))?;
+        if is_temp_file(&name) {
+            continue;
+        }
+
+        let filename = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), &filename, files)?;
+        } else if !file_type.is_file() {
+            continue;
+        } else if let Err(reason) = validate_name(&filename) {
+            warn!("skipping {}: {}", filename, reason);
+        } else {
+            files.insert(filename);
+        }
+    }
+    Ok(())
+}
+
+/// Check that every directory between `data` and `filepath` that exists is a
+/// real directory, rather than a symlink that leads outside of `data`.
+fn check_parents(data: &Path, filepath: &Path) -> Result<(), Error> {
+    let parent = filepath
+        .parent()
+        .and_then(|parent| parent.strip_prefix(data).ok())
+        .ok_or_else(|| format_err!("{} is outside of the library", filepath.display()))?;
+    let mut dir = data.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match symlink_metadata(&dir) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(format_err!("{} is a symlink", dir.display()))
+            }
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Remove `filepath`, along with any directories under `data` that are left
+/// empty.
+fn remove_path(data: &Path, filepath: &Path) -> Result<(), Error> {
+    check_parents(data, filepath)?;
+    remove_file(filepath)?;
+    let mut dir = filepath.parent();
+    while let Some(parent) = dir {
+        if parent == data || remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+    Ok(())
+}
+
+/// Write `filedata` to `filepath` in the data directory `data` with the
+/// metadata of `version`. Returns false, leaving `filepath` untouched, if the
+/// data does not match the hash of `version`.
+fn write_file<R: Read>(
+    data: &Path,
+    filepath: &Path,
+    mut filedata: R,
+    version: &Version,
+) -> Result<bool, Error> {
+    check_parents(data, filepath)?;
+    if let Some(parent) = filepath.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut matched = true;
+    let result = write_atomic(filepath, |file| {
+        let mut hasher = Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = filedata.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n])?;
+        }
+        if hasher.finish() != version.hash {
+            matched = false;
+            return Err(format_err!(
+                "{} does not match its hash",
+                filepath.display()
+            ));
+        }
+
+        file.set_modified(version.modified)?;
+        let mut perms = file.metadata()?.permissions();
+        set_mode(&mut perms, version.executable);
+        file.set_permissions(perms)?;
+        Ok(())
+    });
+    match result {
+        Err(_) if !matched => Ok(false),
+        result => result.map(|()| true),
+    }
+}
+
+/// Write `version` of `filename` from the blob store to `filepath`. Returns
+/// false, leaving `filepath` untouched, if its blobs are missing or damaged;
+/// damaged blobs are dropped so that the next sync pulls them again.
+fn unpack_version(
+    vars: &EnvVars,
+    filename: &str,
+    filepath: &Path,
+    version: &Version,
+) -> Result<bool, Error> {
+    if !version.chunks.iter().all(|c| vars.blobs.contains(c)) {
+        warn!("missing blobs for {}", filename);
+        return Ok(false);
+    }
+    if !write_file(
+        &vars.data,
+        filepath,
+        vars.blobs.open_chunks(&version.chunks),
+        version,
+    )? {
+        warn!("damaged blobs for {}", filename);
+        vars.blobs.drop_damaged(&version.chunks)?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Bring `filename` in the data directory in `vars` in line with its versions
+/// `tags` in `pack`, along with the copies of any versions in conflict.
+fn unpack_entry(
+    vars: &EnvVars,
+    pack: &Library,
+    filename: &str,
+    tags: &HashMap<String, Version>,
+) -> Result<(), Error> {
+    let filepath = match key_path(&vars.data, filename) {
+        Some(filepath) => filepath,
+        None => {
+            warn!("refusing to unpack {}", filename);
+            return Ok(());
+        }
+    };
+    let conflicted = pack.is_conflicted(filename);
+
+    // Clean up the copies of versions that are no longer in conflict.
+    for tag in tags.keys() {
+        let copyname = conflict_name(filename, tag);
+        let copypath = match key_path(&vars.data, &copyname) {
+            Some(copypath) => copypath,
+            None => continue,
+        };
+        if (!conflicted || pack.removed.contains_key(tag)) && copypath.is_file() {
+            info!("removing {}", &copyname);
+            remove_path(&vars.data, &copypath)?;
+        }
+    }
+
+    let winner = match pack.winner(filename) {
+        Some(version) => version,
+        None => {
+            if filepath.is_file() {
+                info!("removing {}", &filename);
+                remove_path(&vars.data, &filepath)?;
+            }
+            return Ok(());
+        }
+    };
+
+    // The file on disk only needs to be checked if it might be holding
+    // a version that was removed or is in conflict.
+    let ondisk = if !filepath.is_file() {
+        None
+    } else if conflicted || tags.keys().any(|tag| pack.removed.contains_key(tag)) {
+        Some(Hash::of_reader(File::open(&filepath)?)?)
+    } else {
+        return Ok(());
+    };
+
+    let current = match ondisk {
+        Some(hash) if pack.live(filename).any(|(_, v)| v.hash == hash) => hash,
+        _ => {
+            info!("unpacking {}", &filename);
+            if !unpack_version(vars, filename, &filepath, winner)? {
+                return Ok(());
+            }
+            winner.hash
+        }
+    };
+
+    for (tag, version) in pack.live(filename) {
+        let copyname = conflict_name(filename, tag);
+        let copypath = match key_path(&vars.data, &copyname) {
+            Some(copypath) => copypath,
+            None => {
+                warn!("refusing to unpack {}", &copyname);
+                continue;
+            }
+        };
+        if version.hash == current || copypath.is_file() {
+            continue;
+        }
+        info!("unpacking {}", &copyname);
+        unpack_version(vars, &copyname, &copypath, version)?;
+    }
+    Ok(())
+}
+
+/// Apply `migrate` to the fields of every version in a serialized library.
+fn migrate_versions<F>(mut state: Value, mut migrate: F) -> Result<Value, Error>
+where
+    F: FnMut(&mut Vec<(Value, Value)>),
+{
+    let set = state
+        .as_map_mut()
+        .and_then(|fields| fields.iter_mut().find(|(k, _)| k.as_text() == Some("set")))
+        .and_then(|(_, set)| set.as_map_mut())
+        .ok_or_else(|| format_err!("library has no set"))?;
+    for (_, tags) in set.iter_mut() {
+        let tags = tags
+            .as_map_mut()
+            .ok_or_else(|| format_err!("malformed library entry"))?;
+        for (_, version) in tags.iter_mut() {
+            let version = version
+                .as_map_mut()
+                .ok_or_else(|| format_err!("malformed library version"))?;
+            migrate(version);
+        }
+    }
+    Ok(state)
+}
+
+impl Library {
+    fn live<'a>(&'a self, filename: &str) -> impl Iterator<Item = (&'a String, &'a Version)> {
+        self.set
+            .get(filename)
+            .into_iter()
+            .flatten()
+            .filter(|(tag, _)| !self.removed.contains_key(*tag))
+    }
+
+    fn is_live(&self, filename: &str) -> bool {
+        self.live(filename).next().is_some()
+    }
+
+    /// The version of `filename` that every replica unpacks when nothing is
+    /// on disk yet.
+    fn winner(&self, filename: &str) -> Option<&Version> {
+        self.live(filename)
+            .min_by_key(|(tag, _)| *tag)
+            .map(|(_, version)| version)
+    }
+
+    /// The latest Lamport timestamp of any version in the library.
+    fn clock(&self) -> u64 {
+        self.set
+            .values()
+            .flat_map(|tags| tags.values())
+            .filter_map(|version| version.origin.as_ref())
+            .map(|origin| origin.clock)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn is_conflicted(&self, filename: &str) -> bool {
+        let mut versions = self.live(filename).map(|(_, version)| version.hash);
+        match versions.next() {
+            Some(first) => versions.any(|hash| hash != first),
+            None => false,
+        }
+    }
+
+    /// Map the name of every possible conflict copy to the filename and tag
+    /// whose version it holds.
+    fn conflict_copies(&self) -> HashMap<String, (String, String)> {
+        let mut copies = HashMap::new();
+        for filename in self.set.keys().filter(|f| self.is_conflicted(f)) {
+            for (tag, _) in self.live(filename) {
+                copies.insert(
+                    conflict_name(filename, tag),
+                    (filename.clone(), tag.clone()),
+                );
+            }
+        }
+        copies
+    }
+
+    /// The files among `files` that are neither in the library nor one of its
+    /// conflict copies, sorted.
+    fn untracked(&self, files: &HashSet<String>) -> Vec<String> {
+        let copies = self.conflict_copies();
+        let mut untracked = files
+            .iter()
+            .filter(|f| !self.is_live(f) && !copies.contains_key(*f))
+            .cloned()
+            .collect::<Vec<String>>();
+        untracked.sort();
+        untracked
+    }
+
+    /// The files among `files`, found in the data directory `data`, that are in
+    /// the library but hold none of its versions, sorted.
+    fn modified(&self, data: &Path, files: &HashSet<String>) -> Result<Vec<String>, Error> {
+        let mut modified = Vec::new();
+        for filename in files.iter().filter(|f| self.is_live(f)) {
+            let filepath = key_path(data, filename)
+                .ok_or_else(|| format_err!("invalid file: {}", filename))?;
+
+            // Packed and unpacked files keep the modification time of their
+            // version, so only the files touched since need to be hashed.
+            let mtime = filepath.metadata()?.modified()?;
+            if self.live(filename).any(|(_, v)| v.modified == mtime) {
+                continue;
+            }
+            let hash = Hash::of_reader(File::open(&filepath)?)?;
+            if !self.live(filename).any(|(_, v)| v.hash == hash) {
+                modified.push(filename.clone());
+            }
+        }
+        modified.sort();
+        Ok(modified)
+    }
+
+    /// Add the contents of `filename` in the data directory as a new version
+    /// from `origin`, and make the file read-only.
+    fn add_file(&mut self, vars: &EnvVars, filename: String, origin: Origin) -> Result<(), Error> {
+        let filepath = key_path(&vars.data, &filename)
+            .ok_or_else(|| format_err!("invalid file: {}", filename))?;
+        let file = File::open(&filepath)?;
+        let metadata = file.metadata()?;
+
+        let (hash, chunks) = vars.blobs.insert_chunks(file)?;
+        let version = Version::new(hash, chunks, &metadata, Some(origin))?;
+        self.set
+            .entry(filename)
+            .or_default()
+            .insert(unique_id()?, version);
+
+        let mut perms = metadata.permissions();
+        perms.set_readonly(true);
+        set_permissions(&filepath, perms)?;
+        Ok(())
+    }
+
+    /// Every version of `filename` that has been added on any replica seen so
+    /// far, oldest first.
+    pub fn provenance(&self, filename: &str) -> Vec<Provenance> {
+        let mut versions = self
+            .set
+            .get(filename)
+            .into_iter()
+            .flatten()
+            .map(|(tag, version)| Provenance {
+                filename: filename.to_string(),
+                tag: tag.clone(),
+                hash: version.hash,
+                replica: version.origin.as_ref().map(|o| o.replica.clone()),
+                clock: version.origin.as_ref().map_or(0, |o| o.clock),
+                live: !self.removed.contains_key(tag),
+            })
+            .collect::<Vec<Provenance>>();
+        versions.sort_by(|a, b| (a.clock, &a.tag).cmp(&(b.clock, &b.tag)));
+        versions
+    }
+
+    /// The provenance of every version of `filenames` in the local
+    /// serialization, or of every file in it if `filenames` is empty.
+    pub fn history(filenames: &[String]) -> Result<Vec<Provenance>, Error> {
+        Library::history_with(&EnvVars::new()?, filenames)
+    }
+
+    /// The provenance of `filenames` in the library in `vars`, as `history`
+    /// reports it.
+    pub fn history_with(vars: &EnvVars, filenames: &[String]) -> Result<Vec<Provenance>, Error> {
+        let (pack, _): (Library, _) = from_file(&vars.crdt, Some(vars))?;
+
+        let mut names = if filenames.is_empty() {
+            pack.set.keys().cloned().collect::<Vec<String>>()
+        } else {
+            filenames.to_vec()
+        };
+        names.sort();
+
+        let mut history = Vec::new();
+        for filename in names.iter() {
+            let versions = pack.provenance(filename);
+            if versions.is_empty() {
+                return Err(format_err!("{} is not in the library", filename));
+            }
+            history.extend(versions);
+        }
+        Ok(history)
+    }
+
+    /// Remove `filenames` from the library and from the data directory.
+    ///
+    /// The removal is recorded in the local serialization, and will propagate
+    /// to other replicas on the next sync. Removing a conflict copy discards
+    /// only the version it holds, which resolves the conflict in favor of the
+    /// remaining versions.
+    pub fn remove(filenames: &[String]) -> Result<(), Error> {
+        Library::remove_with(&EnvVars::new()?, filenames)
+    }
+
+    /// Remove `filenames` from the library in `vars`, as `remove` does.
+    pub fn remove_with(vars: &EnvVars, filenames: &[String]) -> Result<(), Error> {
+        let _lock = vars.lock()?;
+        let replica = vars.replica()?;
+        let (mut pack, mut context): (Library, _) = from_file(&vars.crdt, Some(vars))?;
+        let copies = pack.conflict_copies();
+
+        for filename in filenames {
+            let tags = match copies.get(filename) {
+                Some((_, tag)) => vec![tag.clone()],
+                None => pack
+                    .live(filename)
+                    .map(|(tag, _)| tag.clone())
+                    .collect::<Vec<String>>(),
+            };
+            if tags.is_empty() {
+                return Err(format_err!("{} is not in the library", filename));
+            }
+
+            info!("removing {}", filename);
+            if !copies.contains_key(filename) {
+                for tag in tags.iter() {
+                    let copyname = conflict_name(filename, tag);
+                    match key_path(&vars.data, &copyname) {
+                        Some(copypath) if copypath.is_file() => remove_path(&vars.data, &copypath)?,
+                        _ => (),
+                    }
+                }
+            }
+            let removal = Removal {
+                replica: replica.clone(),
+                seq: context.increment(&replica),
+            };
+            pack.removed
+                .extend(tags.into_iter().map(|tag| (tag, Some(removal.clone()))));
+
+            match key_path(&vars.data, filename) {
+                Some(filepath) if filepath.is_file() => remove_path(&vars.data, &filepath)?,
+                _ => (),
+            }
+        }
+
+        vars.save_history(None)?;
+        to_file(&vars.crdt, &pack, &context)?;
+        Ok(())
+    }
+
+    /// Compare the data directory against the local serialization, and that
+    /// against the states of other replicas as of the last sync, without
+    /// changing any of them or contacting the remote.
+    pub fn status() -> Result<Status, Error> {
+        Library::status_with(&EnvVars::new()?)
+    }
+
+    /// Compare the data directory in `vars` against the library, as `status`
+    /// does.
+    pub fn status_with(vars: &EnvVars) -> Result<Status, Error> {
+        let (local, mut context): (Library, _) = from_file(&vars.crdt, Some(vars))?;
+        let mut merged = local.clone();
+        for refused in merge_cached(&vars.remote_cache()?, None, &mut merged, &mut context)? {
+            warn!("ignoring {}", refused);
+        }
+
+        let mut files = HashSet::<String>::new();
+        walk(&vars.data, "", &mut files)?;
+
+        let mut status = Status {
+            new: local.untracked(&files),
+            ..Status::default()
+        };
+        for filename in merged.set.keys() {
+            if !local.is_live(filename) {
+                if merged.is_live(filename) {
+                    status.remote.push(filename.clone());
+                }
+                continue;
+            }
+            if !files.contains(filename) {
+                status.missing.push(filename.clone());
+            }
+        }
+        status.modified = local.modified(&vars.data, &files)?;
+        status.conflicted = merged.conflicts();
+
+        status.remote.sort();
+        status.missing.sort();
+        Ok(status)
+    }
 }
 
 impl CrdtPack for Library {
+    const SCHEMA_VERSION: u32 = 4;
+
     fn new() -> Library {
         Library {
             set: HashMap::new(),
+            removed: HashMap::new(),
+            rejected: Vec::new(),
         }
     }
 
-    fn unpack(vars: &EnvVars, pack: &Library) -> Result<(), Error> {
-        for (filename, filedata) in pack.set.iter() {
-            let filepath = vars.data.join(filename);
-            if filepath.is_file() {
+    /// The first releases kept the whole contents of every file in the state,
+    /// with nothing else. Each file moves into the blob store as a version
+    /// with no origin, tagged by its hash so that every replica upgrading the
+    /// same file agrees on its tag.
+    fn from_bare(vars: &EnvVars, state: Value) -> Result<Library, Error> {
+        #[derive(Deserialize)]
+        struct Bare {
+            set: HashMap<String, ByteBuf>,
+        }
+
+        let bare: Bare = state.deserialized()?;
+        let mut library = Library::new();
+        for (filename, contents) in bare.set {
+            let (hash, chunks) = vars.blobs.insert_chunks(contents.as_slice())?;
+            let version = Version {
+                hash,
+                chunks,
+                modified: UNIX_EPOCH,
+                executable: false,
+                origin: None,
+            };
+            library
+                .set
+                .entry(filename)
+                .or_default()
+                .insert(hash.to_string(), version);
+        }
+        Ok(library)
+    }
+
+    fn migrate(schema: u32, state: Value) -> Result<Value, Error> {
+        match schema {
+            // Versions gained the replica that added them and a timestamp,
+            // neither of which is known for the versions already added.
+            1 => migrate_versions(state, |version| {
+                version.push((Value::from("replica"), Value::Null));
+                version.push((Value::from("clock"), Value::from(0u64)));
+            }),
+            // The replica and timestamp moved into an origin, alongside the
+            // position of the addition, which is not known for the versions
+            // already added.
+            2 => migrate_versions(state, |version| {
+                let mut take = |key: &str| {
+                    let index = version.iter().position(|(k, _)| k.as_text() == Some(key));
+                    index.map(|i| version.remove(i).1)
+                };
+                let replica = take("replica").unwrap_or(Value::Null);
+                let clock = take("clock").unwrap_or_else(|| Value::from(0u64));
+                let origin = if replica.is_null() {
+                    Value::Null
+                } else {
+                    Value::Map(vec![
+                        (Value::from("replica"), replica),
+                        (Value::from("clock"), clock),
+                        (Value::from("seq"), Value::from(0u64)),
+                    ])
+                };
+                version.push((Value::from("origin"), origin));
+            }),
+            // Removals gained the replica that made them and their position
+            // among its changes, neither of which is known for the removals
+            // already made.
+            3 => {
+                let mut state = state;
+                let removed = state
+                    .as_map_mut()
+                    .and_then(|fields| {
+                        fields
+                            .iter_mut()
+                            .find(|(k, _)| k.as_text() == Some("removed"))
+                    })
+                    .map(|(_, removed)| removed)
+                    .ok_or_else(|| format_err!("library has no removals"))?;
+                let tags = removed
+                    .as_array()
+                    .ok_or_else(|| format_err!("malformed library removals"))?;
+                *removed = Value::Map(tags.iter().map(|tag| (tag.clone(), Value::Null)).collect());
+                Ok(state)
+            }
+            _ => Err(format_err!("cannot upgrade library from schema {}", schema)),
+        }
+    }
+
+    fn plan(
+        vars: &EnvVars,
+        (local, context): (&Library, &VersionVector),
+        (remote, remote_context): (&Library, &VersionVector),
+    ) -> Result<SyncPlan, Error> {
+        let mut files = HashSet::<String>::new();
+        walk(&vars.data, "", &mut files)?;
+
+        // Pack into a copy of the local state, recording only the hash of each
+        // new version so that nothing is written to the blob store.
+        let mut packed = local.clone();
+        let modified = local.modified(&vars.data, &files)?;
+        let untracked = local.untracked(&files);
+        for filename in modified.iter() {
+            let observed = packed
+                .live(filename)
+                .map(|(tag, _)| tag.clone())
+                .collect::<Vec<String>>();
+            packed
+                .removed
+                .extend(observed.into_iter().map(|tag| (tag, None)));
+        }
+        for filename in modified.iter().chain(untracked.iter()) {
+            let filepath = key_path(&vars.data, filename)
+                .ok_or_else(|| format_err!("invalid file: {}", filename))?;
+            let file = File::open(&filepath)?;
+            let metadata = file.metadata()?;
+            let version = Version::new(Hash::of_reader(file)?, Vec::new(), &metadata, None)?;
+            packed
+                .set
+                .entry(filename.clone())
+                .or_default()
+                .insert(unique_id()?, version);
+        }
+
+        let mut merged = packed.clone();
+        merged.merge(context, remote.clone(), remote_context);
+        merged.rejected.clear();
+
+        let mut plan = SyncPlan {
+            conflicts: merged.conflicts(),
+            ..SyncPlan::default()
+        };
+        for (filename, tags) in merged.set.iter() {
+            let winner = match merged.winner(filename) {
+                Some(version) => version,
+                None => {
+                    if files.contains(filename) {
+                        plan.remove.push(filename.clone());
+                    }
+                    continue;
+                }
+            };
+
+            // Mirror `unpack`, which only checks the file on disk if it might
+            // be holding a version that was removed or is in conflict.
+            let conflicted = merged.is_conflicted(filename);
+            let current = if !files.contains(filename) {
+                None
+            } else if conflicted || tags.keys().any(|tag| merged.removed.contains_key(tag)) {
+                let filepath = key_path(&vars.data, filename)
+                    .ok_or_else(|| format_err!("invalid file: {}", filename))?;
+                let hash = Hash::of_reader(File::open(&filepath)?)?;
+                merged
+                    .live(filename)
+                    .any(|(_, v)| v.hash == hash)
+                    .then_some(hash)
+            } else {
                 continue;
+            };
+            let current = match current {
+                Some(hash) => hash,
+                None => {
+                    plan.unpack.push(filename.clone());
+                    winner.hash
+                }
+            };
+            for (tag, version) in merged.live(filename) {
+                let copyname = conflict_name(filename, tag);
+                if version.hash != current && !files.contains(&copyname) {
+                    plan.unpack.push(copyname);
+                }
             }
+        }
 
-            info!("unpacking {}", &filename);
-            let mut file = File::create(&filepath)?;
-            file.write_all(filedata)?;
-            let mut perms = metadata(&filepath)?.permissions();
-            perms.set_readonly(true);
-            set_permissions(&filepath, perms)?;
+        // Every version and removal that the remote has not seen yet is
+        // pushed.
+        for (filename, tags) in packed.set.iter() {
+            let known = remote.set.get(filename);
+            let unseen = tags.keys().any(|tag| {
+                !known.is_some_and(|k| k.contains_key(tag))
+                    || (packed.removed.contains_key(tag) && !remote.removed.contains_key(tag))
+            });
+            if unseen {
+                plan.push.push(filename.clone());
+            }
+        }
+
+        plan.add = modified;
+        plan.add.extend(untracked);
+        plan.add.sort();
+        plan.unpack.sort();
+        plan.remove.sort();
+        plan.push.sort();
+        Ok(plan)
+    }
+
+    fn rollback(vars: &EnvVars, current: &Library, restored: &Library) -> Result<(), Error> {
+        // Files added since the snapshot are removed, as long as they still
+        // hold a version that was added.
+        let mut kept = HashSet::new();
+        for filename in current.set.keys() {
+            if !current.is_live(filename) || restored.is_live(filename) {
+                continue;
+            }
+            let filepath = match key_path(&vars.data, filename) {
+                Some(filepath) if filepath.is_file() => filepath,
+                _ => continue,
+            };
+            let hash = Hash::of_reader(File::open(&filepath)?)?;
+            if current.live(filename).any(|(_, v)| v.hash == hash) {
+                info!("removing {}", filename);
+                remove_path(&vars.data, &filepath)?;
+            } else {
+                warn!("keeping {}, which was modified", filename);
+                kept.insert(filename);
+            }
+        }
+
+        // Files in the snapshot are rewritten unless they already hold one of
+        // its versions, or edits that were never packed; `unpack` only checks
+        // the ones it might have changed.
+        for filename in restored.set.keys() {
+            let (winner, filepath) =
+                match (restored.winner(filename), key_path(&vars.data, filename)) {
+                    (Some(winner), Some(filepath)) => (winner, filepath),
+                    _ => continue,
+                };
+            if filepath.is_file() {
+                let hash = Hash::of_reader(File::open(&filepath)?)?;
+                if restored.live(filename).any(|(_, v)| v.hash == hash) {
+                    continue;
+                }
+                if !current.live(filename).any(|(_, v)| v.hash == hash) {
+                    warn!("keeping {}, which was modified", filename);
+                    kept.insert(filename);
+                    continue;
+                }
+            }
+            info!("restoring {}", filename);
+            unpack_version(vars, filename, &filepath, winner)?;
+        }
+
+        for (filename, tags) in restored.set.iter() {
+            if kept.contains(filename) {
+                continue;
+            }
+            if let Err(e) = unpack_entry(vars, restored, filename, tags) {
+                warn!("failed to unpack {}: {}", filename, e);
+            }
         }
         Ok(())
     }
 
-    fn pack(vars: &EnvVars, pack: &mut Library) -> Result<(), Error> {
+    fn unpack(vars: &EnvVars, pack: &Library) -> Result<Vec<String>, Error> {
+        let mut failed = Vec::new();
+        for (filename, tags) in pack.set.iter() {
+            if let Err(e) = unpack_entry(vars, pack, filename, tags) {
+                warn!("failed to unpack {}: {}", filename, e);
+                failed.push(format!("{}: {}", filename, e));
+            }
+        }
+        Ok(failed)
+    }
+
+    fn pack(vars: &EnvVars, pack: &mut Library, context: &mut VersionVector) -> Result<(), Error> {
         let mut files = HashSet::<String>::new();
-        for entry in read_dir(&vars.data)? {
-            let entry = entry?;
-            if !entry.file_type()?.is_file() {
+        walk(&vars.data, "", &mut files)?;
+
+        let replica = vars.replica()?;
+        let mut clock = pack.clock();
+
+        // Conflict copies are recognized before any edits are recorded, since
+        // an edit can resolve the conflict they belong to.
+        let untracked = pack.untracked(&files);
+
+        // A file edited in place replaces every version it was observed to
+        // hold, so that only edits made concurrently on other replicas end up
+        // in conflict with it.
+        for filename in pack.modified(&vars.data, &files)? {
+            info!("updating {}", filename);
+            let observed = pack
+                .live(&filename)
+                .map(|(tag, _)| tag.clone())
+                .collect::<Vec<String>>();
+            let removal = Removal {
+                replica: replica.clone(),
+                seq: context.increment(&replica),
+            };
+            pack.removed
+                .extend(observed.into_iter().map(|tag| (tag, Some(removal.clone()))));
+            clock += 1;
+            let origin = Origin {
+                replica: replica.clone(),
+                clock,
+                seq: context.increment(&replica),
+            };
+            pack.add_file(vars, filename, origin)?;
+        }
+
+        for filename in untracked {
+            info!("adding {}", filename);
+            clock += 1;
+            let origin = Origin {
+                replica: replica.clone(),
+                clock,
+                seq: context.increment(&replica),
+            };
+            pack.add_file(vars, filename, origin)?;
+        }
+
+        Ok(())
+    }
+
+    fn merge(&mut self, context: &VersionVector, other: Library, other_context: &VersionVector) {
+        // A removed version that the other replica has seen but no longer
+        // holds was purged there, and can be purged here as well.
+        for (name, tags) in self.set.iter_mut() {
+            let theirs = other.set.get(name);
+            tags.retain(|tag, version| {
+                let purged = self.removed.contains_key(tag)
+                    && version.seen_by(other_context)
+                    && !theirs.is_some_and(|t| t.contains_key(tag));
+                if purged {
+                    self.removed.remove(tag);
+                }
+                !purged
+            });
+        }
+        self.set.retain(|_, tags| !tags.is_empty());
+
+        for (name, tags) in other.set.into_iter() {
+            if let Err(reason) = validate_entry(&name, &tags) {
+                self.rejected.push(format!("{}: {}", name, reason));
+                continue;
+            }
+
+            // Likewise, a version this replica has seen but no longer holds
+            // was purged here, and is not brought back by a replica that has
+            // yet to purge it.
+            let ours = self.set.entry(name).or_default();
+            for (tag, version) in tags.into_iter() {
+                if !ours.contains_key(&tag) && !version.seen_by(context) {
+                    ours.insert(tag, version);
+                }
+            }
+        }
+        self.set.retain(|_, tags| !tags.is_empty());
+
+        // Removals follow the same rule, except for those made before they
+        // were counted, which are only kept while their version is.
+        let held = self
+            .set
+            .values()
+            .flat_map(|tags| tags.keys())
+            .cloned()
+            .collect::<HashSet<String>>();
+        for (tag, removal) in other.removed.into_iter() {
+            let purged = match &removal {
+                Some(removal) => context.covers(&removal.replica, removal.seq),
+                None => !held.contains(&tag),
+            };
+            if !purged {
+                self.removed.entry(tag).or_insert(removal);
+            }
+        }
+    }
+
+    fn delta_since(&self, context: &VersionVector) -> Option<Library> {
+        let mut delta = Library::new();
+        for (name, tags) in self.set.iter() {
+            let unseen = tags
+                .iter()
+                .filter(|(_, version)| {
+                    version
+                        .dot()
+                        .is_some_and(|(replica, seq)| !context.covers(replica, seq))
+                })
+                .map(|(tag, version)| (tag.clone(), version.clone()))
+                .collect::<HashMap<String, Version>>();
+            if !unseen.is_empty() {
+                delta.set.insert(name.clone(), unseen);
+            }
+        }
+        for (tag, removal) in self.removed.iter() {
+            if let Some(removal) = removal {
+                if !context.covers(&removal.replica, removal.seq) {
+                    delta.removed.insert(tag.clone(), Some(removal.clone()));
+                }
+            }
+        }
+        Some(delta)
+    }
+
+    fn apply_delta(
+        &mut self,
+        _context: &VersionVector,
+        delta: Library,
+        _delta_context: &VersionVector,
+    ) {
+        // A delta only holds what changed, so nothing can be taken as purged
+        // for being missing from it.
+        for (name, tags) in delta.set.into_iter() {
+            if let Err(reason) = validate_entry(&name, &tags) {
+                self.rejected.push(format!("{}: {}", name, reason));
                 continue;
             }
-            files.insert(
-                entry
-                    .file_name()
-                    .into_string()
-                    .map_err(|e| format_err!("invalid file: {}", e.to_string_lossy()))?,
-            );
+            self.set.entry(name).or_default().extend(tags);
+        }
+        for (tag, removal) in delta.removed.into_iter() {
+            self.removed.entry(tag).or_insert(removal);
+        }
+    }
+
+    fn take_rejected(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    fn purge(&mut self, contexts: &[VersionVector]) -> usize {
+        // Versions and removals without a dot cannot be told apart from ones
+        // a replica has yet to see, so they are kept.
+        let seen = |removal: &Option<Removal>| match removal {
+            Some(removal) => contexts
+                .iter()
+                .all(|context| context.covers(&removal.replica, removal.seq)),
+            None => false,
+        };
+        let removed = &self.removed;
+        for tags in self.set.values_mut() {
+            tags.retain(|tag, version| {
+                let purged = removed.get(tag).is_some_and(seen)
+                    && contexts.iter().all(|context| version.seen_by(context));
+                !purged
+            });
         }
-        let existing_files = pack.set.keys().cloned().collect::<HashSet<String>>();
+        self.set.retain(|_, tags| !tags.is_empty());
+
+        let held = self
+            .set
+            .values()
+            .flat_map(|tags| tags.keys())
+            .cloned()
+            .collect::<HashSet<String>>();
+        let before = self.removed.len();
+        self.removed
+            .retain(|tag, removal| held.contains(tag) || !seen(removal));
+        before - self.removed.len()
+    }
+
+    fn blobs(&self) -> HashSet<Hash> {
+        self.set
+            .keys()
+            .flat_map(|filename| self.live(filename))
+            .flat_map(|(_, version)| version.chunks.iter().copied())
+            .collect()
+    }
+
+    fn conflicts(&self) -> Vec<String> {
+        let mut conflicts = self
+            .set
+            .keys()
+            .filter(|f| self.is_conflicted(f))
+            .cloned()
+            .collect::<Vec<String>>();
+        conflicts.sort();
+        conflicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        fs::{read, write},
+    };
+
+    use super::*;
+    use crate::testing::{profile, write_bare, TempDir};
+
+    /// Write `filename`, holding its own name, to the data directory and
+    /// pack it.
+    fn add(vars: &EnvVars, library: &mut Library, context: &mut VersionVector, filename: &str) {
+        let path = key_path(&vars.data, filename).unwrap();
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, filename.as_bytes()).unwrap();
+        Library::pack(vars, library, context).unwrap();
+    }
+
+    /// Record the removal of `filename` the way `remove` does.
+    fn remove(vars: &EnvVars, library: &mut Library, context: &mut VersionVector, filename: &str) {
+        let replica = vars.replica().unwrap();
+        let removal = Removal {
+            seq: context.increment(&replica),
+            replica,
+        };
+        let tags = library
+            .live(filename)
+            .map(|(tag, _)| tag.clone())
+            .collect::<Vec<String>>();
+        assert!(!tags.is_empty());
+        library
+            .removed
+            .extend(tags.into_iter().map(|tag| (tag, Some(removal.clone()))));
+    }
+
+    #[test]
+    fn names_conflict_copies() {
+        assert_eq!(conflict_name("book.pdf", "t1"), "book.conflict-t1.pdf");
+        assert_eq!(conflict_name("a.b/notes", "t1"), "a.b/notes.conflict-t1");
+        assert_eq!(conflict_name("a/.hidden", "t1"), "a/.hidden.conflict-t1");
+        assert_eq!(conflict_name("a/b.tar.gz", "t1"), "a/b.tar.conflict-t1.gz");
+    }
+
+    #[test]
+    fn removal_wins_over_observed_adds() {
+        let root = TempDir::new();
+        let a = profile(root.path(), "a", &root.path().join("remote"));
+        let mut library = Library::new();
+        let mut context = VersionVector::new();
+        add(&a, &mut library, &mut context, "book.txt");
+        let (mut other, mut other_context) = (Library::new(), VersionVector::new());
+        other.merge(&other_context, library.clone(), &context);
+        other_context.merge(&context);
+
+        remove(&a, &mut library, &mut context, "book.txt");
+        assert!(!library.is_live("book.txt"));
+        other.merge(&other_context, library.clone(), &context);
+        assert!(!other.is_live("book.txt"));
+
+        // Merging the other way does not bring it back either.
+        library.merge(&context, other, &other_context);
+        assert!(!library.is_live("book.txt"));
+    }
 
-        for new_file in files.difference(&existing_files) {
-            let filename = vars.data.join(new_file);
-            let mut file = File::open(&filename)?;
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf)?;
+    #[test]
+    fn concurrent_adds_survive_removal() {
+        let root = TempDir::new();
+        let a = profile(root.path(), "a", &root.path().join("remote"));
+        let b = profile(root.path(), "b", &root.path().join("remote"));
+        let mut library = Library::new();
+        let mut context = VersionVector::new();
+        add(&a, &mut library, &mut context, "book.txt");
 
-            info!("adding {}", new_file);
-            pack.set.insert(new_file.clone(), ByteBuf::from(buf));
+        // Replica b adds the same name without having seen a's version, while
+        // a removes the version it holds.
+        let (mut other, mut other_context) = (Library::new(), VersionVector::new());
+        add(&b, &mut other, &mut other_context, "book.txt");
+        remove(&a, &mut library, &mut context, "book.txt");
 
-            let mut perms = metadata(&filename)?.permissions();
-            perms.set_readonly(true);
-            set_permissions(&filename, perms)?;
+        library.merge(&context, other, &other_context);
+        assert!(library.is_live("book.txt"));
+        assert_eq!(library.live("book.txt").count(), 1);
+    }
+
+    /// The tags held for each filename, and every removed tag.
+    fn summary(library: &Library) -> (BTreeMap<String, BTreeSet<String>>, BTreeSet<String>) {
+        let set = library
+            .set
+            .iter()
+            .map(|(name, tags)| (name.clone(), tags.keys().cloned().collect()))
+            .collect();
+        (set, library.removed.keys().cloned().collect())
+    }
+
+    #[test]
+    fn provenance_follows_every_version() {
+        let root = TempDir::new();
+        let a = profile(root.path(), "a", &root.path().join("remote"));
+        let b = profile(root.path(), "b", &root.path().join("remote"));
+        let mut library = Library::new();
+        let mut context = VersionVector::new();
+        add(&a, &mut library, &mut context, "book.txt");
+
+        // Replica b replaces the version it has seen.
+        let (mut other, mut other_context) = (Library::new(), VersionVector::new());
+        other.merge(&other_context, library.clone(), &context);
+        other_context.merge(&context);
+        write(b.data.join("book.txt"), "edited on b").unwrap();
+        Library::pack(&b, &mut other, &mut other_context).unwrap();
+        library.merge(&context, other, &other_context);
+
+        let versions = library.provenance("book.txt");
+        assert_eq!(versions.len(), 2);
+        let (first, second) = (&versions[0], &versions[1]);
+        assert_eq!(first.replica, Some(a.replica().unwrap()));
+        assert_eq!(second.replica, Some(b.replica().unwrap()));
+        assert!(first.clock < second.clock);
+        assert!(!first.live);
+        assert!(second.live);
+        assert_eq!(second.hash, Hash::of(b"edited on b"));
+        assert!(library.provenance("other.txt").is_empty());
+    }
+
+    #[test]
+    fn history_covers_the_whole_library() {
+        let root = TempDir::new();
+        let vars = profile(root.path(), "a", &root.path().join("remote"));
+        let mut library = Library::new();
+        let mut context = VersionVector::new();
+        add(&vars, &mut library, &mut context, "notes.txt");
+        add(&vars, &mut library, &mut context, "book.txt");
+        remove(&vars, &mut library, &mut context, "notes.txt");
+        to_file(&vars.crdt, &library, &context).unwrap();
+
+        let history = Library::history_with(&vars, &[]).unwrap();
+        let names = history
+            .iter()
+            .map(|version| (version.filename.as_str(), version.live))
+            .collect::<Vec<_>>();
+        assert_eq!(names, [("book.txt", true), ("notes.txt", false)]);
+
+        let history = Library::history_with(&vars, &["notes.txt".to_string()]).unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(Library::history_with(&vars, &["other.txt".to_string()]).is_err());
+    }
+
+    #[test]
+    fn packs_files_from_before_the_epoch() {
+        let root = TempDir::new();
+        let vars = profile(root.path(), "a", &root.path().join("remote"));
+        let path = vars.data.join("old.txt");
+        write(&path, "old").unwrap();
+        let before = UNIX_EPOCH - std::time::Duration::from_secs(86400);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(before)
+            .unwrap();
+
+        let mut library = Library::new();
+        let mut context = VersionVector::new();
+        Library::pack(&vars, &mut library, &mut context).unwrap();
+        let (_, version) = library.live("old.txt").next().unwrap();
+        assert_eq!(version.modified, UNIX_EPOCH);
+        to_file(&vars.crdt, &library, &context).unwrap();
+    }
+
+    #[test]
+    fn validates_names() {
+        for name in ["book.pdf", "a/b/c.txt", "..hidden", "a/.b", "console/x"] {
+            assert_eq!(validate_name(name), Ok(()), "{}", name);
         }
+        for name in [
+            "",
+            "..",
+            "../x",
+            "a/../../x",
+            "a/..",
+            "./a",
+            "a/./b",
+            "/etc/passwd",
+            "a//b",
+            "a/",
+            "a\\..\\b",
+            "nul\0byte",
+            "CON",
+            "a/aux.txt",
+            "Lpt1",
+        ] {
+            assert!(validate_name(name).is_err(), "{:?}", name);
+            assert!(key_path(Path::new("/data"), name).is_none(), "{:?}", name);
+        }
+        assert_eq!(
+            key_path(Path::new("/data"), "a/b.txt").unwrap(),
+            Path::new("/data/a/b.txt")
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn merge_rejects_unsafe_names() {
+        let root = TempDir::new();
+        let vars = profile(root.path(), "a", &root.path().join("remote"));
+        let mut other = Library::new();
+        let mut other_context = VersionVector::new();
+        add(&vars, &mut other, &mut other_context, "book.pdf");
+        let version = other.set["book.pdf"].values().next().unwrap().clone();
+        for name in ["../escape", "/etc/passwd", "a/../../b"] {
+            let tags = HashMap::from([(unique_id().unwrap(), version.clone())]);
+            other.set.insert(name.to_string(), tags);
+        }
+
+        let mut library = Library::new();
+        library.merge(&VersionVector::new(), other, &other_context);
+        assert_eq!(library.set.keys().collect::<Vec<_>>(), ["book.pdf"]);
+        assert_eq!(library.take_rejected().len(), 3);
+        assert!(library.take_rejected().is_empty());
     }
 
-    fn merge(&mut self, other: Library) {
-        let other = other.set;
-        for (name, contents) in other.into_iter() {
-            self.set.entry(name).or_insert(contents);
+    #[test]
+    fn delta_matches_full_state() {
+        let root = TempDir::new();
+        let vars = profile(root.path(), "a", &root.path().join("remote"));
+        let mut library = Library::new();
+        let mut context = VersionVector::new();
+        add(&vars, &mut library, &mut context, "one.txt");
+        add(&vars, &mut library, &mut context, "two.txt");
+        let (old, old_context) = (library.clone(), context.clone());
+
+        add(&vars, &mut library, &mut context, "dir/three.txt");
+        remove(&vars, &mut library, &mut context, "one.txt");
+
+        let delta = library.delta_since(&old_context).unwrap();
+        assert_eq!(delta.set.keys().collect::<Vec<_>>(), ["dir/three.txt"]);
+        assert_eq!(delta.removed.len(), 1);
+
+        let mut from_delta = old.clone();
+        from_delta.apply_delta(&old_context, delta, &context);
+        let mut from_state = old;
+        from_state.merge(&old_context, library.clone(), &context);
+        assert_eq!(summary(&from_delta), summary(&library));
+        assert_eq!(summary(&from_state), summary(&library));
+
+        // Nothing is left to send once the other side has caught up.
+        let delta = library.delta_since(&context).unwrap();
+        assert!(delta.set.is_empty() && delta.removed.is_empty());
+    }
+
+    #[test]
+    fn purge_waits_for_every_replica() {
+        let root = TempDir::new();
+        let a = profile(root.path(), "a", &root.path().join("remote"));
+        let mut library = Library::new();
+        let mut context = VersionVector::new();
+        add(&a, &mut library, &mut context, "kept.txt");
+        add(&a, &mut library, &mut context, "gone.txt");
+
+        // Replica b has seen the addition, but not the removal.
+        let (mut other, mut other_context) = (Library::new(), VersionVector::new());
+        other.merge(&other_context, library.clone(), &context);
+        other_context.merge(&context);
+        remove(&a, &mut library, &mut context, "gone.txt");
+
+        assert_eq!(library.purge(&[context.clone(), other_context.clone()]), 0);
+        assert!(!library.removed.is_empty());
+
+        other.merge(&other_context, library.clone(), &context);
+        other_context.merge(&context);
+        assert_eq!(library.purge(&[context.clone(), other_context.clone()]), 1);
+        assert!(library.removed.is_empty());
+        assert_eq!(library.set.keys().collect::<Vec<_>>(), ["kept.txt"]);
+
+        // Merging the unpurged state back in does not bring the version back,
+        // and merging the purged state purges it there too.
+        library.merge(&context, other.clone(), &other_context);
+        assert_eq!(summary(&library).0.len(), 1);
+        assert!(library.removed.is_empty());
+        other.merge(&other_context, library.clone(), &context);
+        assert_eq!(summary(&other), summary(&library));
+    }
+
+    #[test]
+    fn upgrades_bare_state() {
+        let root = TempDir::new();
+        let vars = profile(root.path(), "a", &root.path().join("remote"));
+        write_bare(
+            &vars.crdt,
+            &[("book.pdf", b"contents of a book"), ("notes.txt", b"")],
+        );
+
+        let (library, context): (Library, _) = from_file(&vars.crdt, Some(&vars)).unwrap();
+        assert_eq!(context, VersionVector::new());
+        assert!(library.removed.is_empty());
+        assert_eq!(library.set.len(), 2);
+        for (filename, tags) in library.set.iter() {
+            let (tag, version) = tags.iter().next().unwrap();
+            assert_eq!(tags.len(), 1, "{}", filename);
+            assert_eq!(*tag, version.hash.to_string());
+            assert!(version.origin.is_none());
+            assert_eq!(version.modified, UNIX_EPOCH);
+            assert!(version.chunks.iter().all(|c| vars.blobs.contains(c)));
         }
+
+        Library::unpack(&vars, &library).unwrap();
+        assert_eq!(
+            read(vars.data.join("book.pdf")).unwrap(),
+            b"contents of a book"
+        );
+        assert_eq!(read(vars.data.join("notes.txt")).unwrap(), b"");
+    }
+
+    #[test]
+    fn bare_state_needs_a_profile() {
+        let root = TempDir::new();
+        let vars = profile(root.path(), "a", &root.path().join("remote"));
+        write_bare(&vars.crdt, &[("book.pdf", b"contents of a book")]);
+        assert!(from_file::<Library>(&vars.crdt, None).is_err());
+    }
+
+    #[test]
+    fn replicas_agree_on_upgraded_tags() {
+        let root = TempDir::new();
+        let a = profile(root.path(), "a", &root.path().join("remote"));
+        let b = profile(root.path(), "b", &root.path().join("remote"));
+        write_bare(&a.crdt, &[("book.pdf", b"contents of a book")]);
+        write_bare(&b.crdt, &[("book.pdf", b"contents of a book")]);
+
+        let (mut library, context): (Library, _) = from_file(&a.crdt, Some(&a)).unwrap();
+        let (other, other_context): (Library, _) = from_file(&b.crdt, Some(&b)).unwrap();
+        library.merge(&context, other, &other_context);
+        assert_eq!(library.set["book.pdf"].len(), 1);
+        assert!(library.conflicts().is_empty());
     }
 }
@@ -0,0 +1,90 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Scratch profiles for tests, each in a temporary directory of its own.
+
+use std::{
+    env::{set_var, temp_dir},
+    fs::{create_dir_all, remove_dir_all, write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use ciborium::{ser::into_writer, value::Value};
+use xdg::BaseDirectories;
+
+use crate::{blobs::BlobStore, unique_id, EnvVars};
+
+/// A directory that is removed along with everything in it when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> TempDir {
+        let path = temp_dir().join(format!("magpie-test-{}", unique_id().unwrap()));
+        create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// Held while the XDG variables are pointed at a test profile, since the
+/// environment is shared by every test thread.
+static XDG_ENV: Mutex<()> = Mutex::new(());
+
+/// A profile named `name` under `root`, with its own XDG directories and data
+/// directory, syncing with `url`.
+pub(crate) fn profile(root: &Path, name: &str, url: &Path) -> EnvVars {
+    let home = root.join(name);
+    let data = home.join("library");
+    create_dir_all(&data).unwrap();
+
+    let xdg_dirs = {
+        let _env = XDG_ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (var, dir) in [
+            ("XDG_DATA_HOME", "data"),
+            ("XDG_CONFIG_HOME", "config"),
+            ("XDG_CACHE_HOME", "cache"),
+            ("XDG_STATE_HOME", "state"),
+        ] {
+            set_var(var, home.join(dir));
+        }
+        BaseDirectories::with_profile("magpie", "test").unwrap()
+    };
+    let crdt = xdg_dirs.get_data_file("local.cbor");
+    let blobs = BlobStore::new(&xdg_dirs.get_data_file("blobs"));
+    create_dir_all(crdt.parent().unwrap()).unwrap();
+
+    EnvVars {
+        appname: "magpie".to_string(),
+        channel: "test".to_string(),
+        xdg_dirs,
+        crdt,
+        data,
+        blobs,
+        url: url.to_string_lossy().into_owned(),
+        lock_timeout: None,
+    }
+}
+
+/// Write `files` to `path` the way the first releases saved their state: a
+/// bare map from each filename to its contents.
+pub(crate) fn write_bare(path: &Path, files: &[(&str, &[u8])]) {
+    let set = files
+        .iter()
+        .map(|(name, contents)| (Value::from(*name), Value::Bytes(contents.to_vec())))
+        .collect();
+    let state = Value::Map(vec![(Value::from("set"), Value::Map(set))]);
+    let mut out = Vec::new();
+    into_writer(&state, &mut out).unwrap();
+    write(path, out).unwrap();
+}
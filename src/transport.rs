@@ -0,0 +1,1118 @@
+// SPDX-FileCopyrightText: 2023 Brian Kubisiak <brian@kubisiak.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    ffi::OsString,
+    fs::{create_dir_all, read, read_dir, remove_dir, remove_dir_all, remove_file},
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use failure::{format_err, Error};
+
+use crate::{
+    blobs::{blob_path, parse_blob_path, BlobStore},
+    copy_atomic,
+    crypto::{self, Key},
+    hash::Hash,
+    is_replica_file, is_temp_file, write_atomic, EnvVars, DELTA_EXTENSION,
+};
+
+/// A way of moving serialized state and blobs to and from the remote.
+///
+/// Every replica pushes its state to its own files on the remote: a snapshot
+/// named `<replica>.cbor`, and the deltas pushed since, so pushes from
+/// different replicas never overwrite each other.
+///
+/// The url itself used to name the one state file that every replica pushed
+/// to and pulled from. It is now only the prefix of the files and directories
+/// that make up the remote. A state file left there by the first releases is
+/// read once by each replica, which merges it in as if it came from a replica
+/// that has not seen anything else.
+pub trait Transport {
+    /// Copy the state files of every replica on the remote into the directory
+    /// `dir`, removing any state files in `dir` that are not on the remote.
+    fn pull(&self, dir: &Path) -> Result<(), Error>;
+
+    /// Make the state files of `replica` on the remote match those in the
+    /// directory `dir`, removing any that are not in `dir`. Files are copied
+    /// before any are removed.
+    fn push(&self, dir: &Path, replica: &str) -> Result<(), Error>;
+
+    /// Copy every blob in `hashes` that the remote has into the directory
+    /// `dir`, laid out the way a [`BlobStore`] is.
+    fn pull_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error>;
+
+    /// Copy every blob in `hashes` from the directory `dir`, laid out the way
+    /// a [`BlobStore`] is, to the remote, skipping those the remote already
+    /// has.
+    fn push_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error>;
+
+    /// Every blob on the remote, with the size it takes up there.
+    fn list_blobs(&self) -> Result<Vec<(Hash, u64)>, Error>;
+
+    /// Remove every blob in `hashes` from the remote. `dir` is a local
+    /// directory that holds none of them, for use as scratch space.
+    fn remove_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error>;
+
+    /// The name that the blob `hash` goes by on the remote, as listed by
+    /// `list_blobs` and passed to `remove_blobs`.
+    fn blob_name(&self, hash: &Hash) -> Hash {
+        *hash
+    }
+
+    /// Copy the header describing the remote as a whole to `path`, returning
+    /// whether the remote has one.
+    fn pull_header(&self, path: &Path) -> Result<bool, Error>;
+
+    /// Copy `path` to the remote as its header, unless it already has one.
+    fn push_header(&self, path: &Path) -> Result<(), Error>;
+
+    /// Copy the single state file that the first releases shared between
+    /// every replica, at the url itself, to `path`, returning whether the
+    /// remote still has one.
+    fn pull_legacy(&self, path: &Path) -> Result<bool, Error>;
+
+    /// Describe every object that pulls since the last call refused to pass
+    /// on, such as one that could not be decrypted.
+    fn take_refused(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Open the transport for the remote in `vars`, encrypting everything that
+/// goes through it if a key is configured.
+///
+/// Encrypted objects are kept apart from plain ones, under `<url>.sealed`.
+/// Whatever was pushed in the clear before the key was configured is moved
+/// over once, by the first replica with the key, and anything pushed in the
+/// clear after that is ignored, so every replica should be given the key.
+pub fn open(vars: &EnvVars) -> Result<Box<dyn Transport>, Error> {
+    let plain = from_url(&vars.url)?;
+    match vars.secret()? {
+        Some(secret) => {
+            let sealed = from_url(&format!("{}.sealed", vars.url))?;
+            let scratch = vars.xdg_dirs.create_cache_directory("encrypted")?;
+            Ok(Box::new(Encrypted::new(sealed, plain, &secret, &scratch)?))
+        }
+        None => Ok(plain),
+    }
+}
+
+/// Open the transport for the remote in `vars` as `open` does, but without
+/// changing anything on the remote, for a look at what is there. Sealed copies
+/// of encrypted objects are kept in `scratch`. An encrypted remote that has
+/// no header yet is read as plain, since that is what it will start from.
+pub fn open_read_only(vars: &EnvVars, scratch: &Path) -> Result<Box<dyn Transport>, Error> {
+    let plain = from_url(&vars.url)?;
+    match vars.secret()? {
+        Some(secret) => {
+            let sealed = from_url(&format!("{}.sealed", vars.url))?;
+            create_dir_all(scratch)?;
+            match Encrypted::open(sealed, plain, &secret, scratch)? {
+                Some(encrypted) => Ok(Box::new(encrypted)),
+                None => from_url(&vars.url),
+            }
+        }
+        None => Ok(plain),
+    }
+}
+
+/// Pick the transport for `url` based on its scheme. Urls without a scheme
+/// are treated the same way rsync treats them: `host:path` is remote, and
+/// anything else is a local path.
+pub fn from_url(url: &str) -> Result<Box<dyn Transport>, Error> {
+    match url.split_once("://") {
+        None if is_rsync_remote(url) => Ok(Box::new(Rsync::new(url))),
+        None => Ok(Box::new(LocalDir::new(Path::new(url)))),
+        Some(("file", path)) => Ok(Box::new(LocalDir::new(Path::new(path)))),
+        Some(("rsync", _)) => Ok(Box::new(Rsync::new(url))),
+        Some((scheme, _)) => Err(format_err!("unsupported url scheme: {}", scheme)),
+    }
+}
+
+fn is_rsync_remote(url: &str) -> bool {
+    match url.find(':') {
+        Some(colon) => !url[..colon].contains('/'),
+        None => false,
+    }
+}
+
+/// Append `suffix` to the last component of `path`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path.as_os_str());
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn dir_url(dir: &Path) -> String {
+    format!("{}/", dir.display())
+}
+
+/// Whether the state file `filename` never changes once written, so a copy
+/// that already exists is up to date.
+fn is_immutable(filename: &str) -> bool {
+    filename.ends_with(&format!(".{}", DELTA_EXTENSION))
+}
+
+/// Copy every state file from `src` to `dest` that `select` picks, skipping
+/// deltas that `dest` already has. Afterwards, remove any picked files in
+/// `dest` that are not in `src`. `open` reads each file out of `src`, or
+/// refuses it by returning `None`, in which case it is left out of `dest`.
+fn mirror<S, O>(src: &Path, dest: &Path, select: S, open: O) -> Result<(), Error>
+where
+    S: Fn(&str) -> bool,
+    O: Fn(&Path) -> Result<Option<Vec<u8>>, Error>,
+{
+    create_dir_all(dest)?;
+
+    let mut copied = HashSet::new();
+    if src.is_dir() {
+        for entry in read_dir(src)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if is_temp_file(&name) || !select(&name) {
+                continue;
+            }
+            let destpath = dest.join(&name);
+            if !is_immutable(&name) || !destpath.is_file() {
+                match open(&src.join(&name))? {
+                    Some(data) => write_atomic(&destpath, |out| Ok(out.write_all(&data)?))?,
+                    None => continue,
+                }
+            }
+            copied.insert(name);
+        }
+    }
+
+    for entry in read_dir(dest)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if select(&name) && !copied.contains(&name) {
+            remove_file(dest.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Transport that shells out to rsync. The replica states are kept in a
+/// directory named after the url with a `.replicas` suffix, and the blobs in
+/// one with a `.blobs` suffix, laid out the way a [`BlobStore`] is.
+pub struct Rsync {
+    url: String,
+}
+
+impl Rsync {
+    pub fn new(url: &str) -> Rsync {
+        Rsync {
+            url: url.to_string(),
+        }
+    }
+
+    fn replicas(&self) -> String {
+        format!("{}.replicas/", self.url)
+    }
+
+    fn blobs(&self) -> String {
+        format!("{}.blobs/", self.url)
+    }
+
+    fn header(&self) -> String {
+        format!("{}.header", self.url)
+    }
+
+    fn copy(&self, args: &[&str], src: &str, dest: &str) -> Result<(), Error> {
+        log::trace!("beginning rsync {} -> {}", src, dest);
+        // TODO: log the output instead of printing it
+        let result = Command::new("rsync")
+            .arg("--compress")
+            .arg("--verbose")
+            .arg("--ignore-missing-args")
+            .args(args)
+            .arg(src)
+            .arg(dest)
+            .status()?;
+        if !result.success() {
+            return Err(format_err!("rsync {} -> {} failed: {}", src, dest, result));
+        }
+        log::trace!("rsync {} -> {} complete", src, dest);
+        Ok(())
+    }
+
+    /// Run rsync with the paths of `hashes` (relative to `src`) passed on
+    /// stdin.
+    fn copy_blobs(&self, hashes: &[Hash], src: &str, dest: &str) -> Result<(), Error> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+
+        log::trace!(
+            "beginning rsync of {} blobs {} -> {}",
+            hashes.len(),
+            src,
+            dest
+        );
+        let mut child = Command::new("rsync")
+            .arg("--compress")
+            .arg("--verbose")
+            .arg("--ignore-missing-args")
+            .arg("--ignore-existing")
+            .arg("--files-from=-")
+            .arg(src)
+            .arg(dest)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            for hash in hashes {
+                writeln!(stdin, "{}", blob_path(hash))?;
+            }
+        }
+        let result = child.wait()?;
+        if !result.success() {
+            return Err(format_err!("rsync {} -> {} failed: {}", src, dest, result));
+        }
+        log::trace!("rsync of blobs {} -> {} complete", src, dest);
+        Ok(())
+    }
+}
+
+/// Parse one line of `rsync --list-only` output into the name and size of
+/// the file it describes, or `None` for anything that is not a regular file.
+fn parse_listing(line: &str) -> Option<(&str, u64)> {
+    let fields = line.split_whitespace().collect::<Vec<&str>>();
+    match fields.as_slice() {
+        [mode, size, _date, _time, name] if mode.starts_with('-') => {
+            Some((name, size.replace(',', "").parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+impl Transport for Rsync {
+    fn pull(&self, dir: &Path) -> Result<(), Error> {
+        self.copy(
+            &["--recursive", "--delete"],
+            &self.replicas(),
+            &dir_url(dir),
+        )
+    }
+
+    fn push(&self, dir: &Path, replica: &str) -> Result<(), Error> {
+        let include = format!("--include=/{}.*", replica);
+        self.copy(
+            &["--recursive", "--delete-after", &include, "--exclude=*"],
+            &dir_url(dir),
+            &self.replicas(),
+        )
+    }
+
+    fn pull_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error> {
+        self.copy_blobs(hashes, &self.blobs(), &dir_url(dir))
+    }
+
+    fn push_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error> {
+        self.copy_blobs(hashes, &dir_url(dir), &self.blobs())
+    }
+
+    fn list_blobs(&self) -> Result<Vec<(Hash, u64)>, Error> {
+        let output = Command::new("rsync")
+            .arg("--list-only")
+            .arg("--recursive")
+            .arg("--ignore-missing-args")
+            .arg(self.blobs())
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(format_err!(
+                "rsync --list-only {} failed: {}",
+                self.blobs(),
+                output.status
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(parse_listing)
+            .filter_map(|(path, size)| Some((parse_blob_path(path)?, size)))
+            .collect())
+    }
+
+    fn remove_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+
+        // None of the blobs are in `dir`, so mirroring just them from there
+        // deletes them from the remote. Their subdirectories have to be there
+        // for rsync to look inside the ones on the remote.
+        log::trace!("beginning rsync removal of {} blobs", hashes.len());
+        let mut shards = HashSet::new();
+        for hash in hashes {
+            let path = blob_path(hash);
+            if let Some((shard, _)) = path.split_once('/') {
+                shards.insert(shard.to_string());
+            }
+        }
+        for shard in shards.iter() {
+            create_dir_all(dir.join(shard))?;
+        }
+        let mut child = Command::new("rsync")
+            .arg("--verbose")
+            .arg("--recursive")
+            .arg("--delete")
+            .arg("--include-from=-")
+            .arg("--exclude=*")
+            .arg(dir_url(dir))
+            .arg(self.blobs())
+            .stdin(Stdio::piped())
+            .spawn()?;
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            for shard in shards.iter() {
+                writeln!(stdin, "/{}/", shard)?;
+            }
+            for hash in hashes {
+                writeln!(stdin, "/{}", blob_path(hash))?;
+            }
+        }
+        let result = child.wait()?;
+        for shard in shards.iter() {
+            let _ = remove_dir(dir.join(shard));
+        }
+        if !result.success() {
+            return Err(format_err!(
+                "rsync removal from {} failed: {}",
+                self.blobs(),
+                result
+            ));
+        }
+        log::trace!("rsync removal of blobs complete");
+        Ok(())
+    }
+
+    fn pull_header(&self, path: &Path) -> Result<bool, Error> {
+        if path.is_file() {
+            remove_file(path)?;
+        }
+        self.copy(&[], &self.header(), &path.display().to_string())?;
+        Ok(path.is_file())
+    }
+
+    fn push_header(&self, path: &Path) -> Result<(), Error> {
+        self.copy(
+            &["--ignore-existing"],
+            &path.display().to_string(),
+            &self.header(),
+        )
+    }
+
+    fn pull_legacy(&self, path: &Path) -> Result<bool, Error> {
+        if path.is_file() {
+            remove_file(path)?;
+        }
+        self.copy(&[], &self.url, &path.display().to_string())?;
+        Ok(path.is_file())
+    }
+}
+
+/// Transport for a remote that is reachable as a local path, such as a
+/// network mount or a removable drive. The layout matches [`Rsync`].
+pub struct LocalDir {
+    path: PathBuf,
+}
+
+impl LocalDir {
+    pub fn new(path: &Path) -> LocalDir {
+        LocalDir {
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn replicas(&self) -> PathBuf {
+        with_suffix(&self.path, ".replicas")
+    }
+
+    fn blobs(&self) -> PathBuf {
+        with_suffix(&self.path, ".blobs")
+    }
+
+    fn header(&self) -> PathBuf {
+        with_suffix(&self.path, ".header")
+    }
+}
+
+/// Copy every blob in `hashes` that the store in `src` has to the one in
+/// `dest`, unless it is already there.
+fn copy_blobs(hashes: &[Hash], src: &Path, dest: &Path) -> Result<(), Error> {
+    let (src, dest) = (BlobStore::new(src), BlobStore::new(dest));
+    for hash in hashes {
+        let (srcpath, destpath) = (src.path(hash), dest.path(hash));
+        if !srcpath.is_file() || destpath.is_file() {
+            continue;
+        }
+        if let Some(shard) = destpath.parent() {
+            create_dir_all(shard)?;
+        }
+        copy_atomic(&srcpath, &destpath)?;
+    }
+    Ok(())
+}
+
+impl Transport for LocalDir {
+    fn pull(&self, dir: &Path) -> Result<(), Error> {
+        mirror(
+            &self.replicas(),
+            dir,
+            |_| true,
+            |path| Ok(Some(read(path)?)),
+        )
+    }
+
+    fn push(&self, dir: &Path, replica: &str) -> Result<(), Error> {
+        mirror(
+            dir,
+            &self.replicas(),
+            |name| is_replica_file(name, replica),
+            |path| Ok(Some(read(path)?)),
+        )
+    }
+
+    fn pull_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error> {
+        copy_blobs(hashes, &self.blobs(), dir)
+    }
+
+    fn push_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error> {
+        copy_blobs(hashes, dir, &self.blobs())
+    }
+
+    fn list_blobs(&self) -> Result<Vec<(Hash, u64)>, Error> {
+        BlobStore::new(&self.blobs()).list()
+    }
+
+    fn remove_blobs(&self, hashes: &[Hash], _dir: &Path) -> Result<(), Error> {
+        let store = BlobStore::new(&self.blobs());
+        for hash in hashes {
+            store.remove(hash)?;
+        }
+        Ok(())
+    }
+
+    fn pull_header(&self, path: &Path) -> Result<bool, Error> {
+        if !self.header().is_file() {
+            return Ok(false);
+        }
+        copy_atomic(&self.header(), path)?;
+        Ok(true)
+    }
+
+    fn push_header(&self, path: &Path) -> Result<(), Error> {
+        if !self.header().is_file() {
+            if let Some(parent) = self.header().parent() {
+                create_dir_all(parent)?;
+            }
+            copy_atomic(path, &self.header())?;
+        }
+        Ok(())
+    }
+
+    fn pull_legacy(&self, path: &Path) -> Result<bool, Error> {
+        if !self.path.is_file() {
+            return Ok(false);
+        }
+        copy_atomic(&self.path, path)?;
+        Ok(true)
+    }
+}
+
+/// Marks the header of an encrypted remote, which holds the salt that the key
+/// for the library is derived with.
+const SALT_MAGIC: &[u8; 8] = b"magpie\x00K";
+const SALT_LEN: usize = 32;
+
+/// The replica that the state file `filename` belongs to.
+fn replica_of(filename: &str) -> &str {
+    filename.split('.').next().unwrap_or(filename)
+}
+
+/// The replicas with state files in the directory `dir`.
+fn replicas_in(dir: &Path) -> Result<HashSet<String>, Error> {
+    let mut replicas = HashSet::new();
+    if !dir.is_dir() {
+        return Ok(replicas);
+    }
+    for entry in read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !is_temp_file(&name) {
+            replicas.insert(replica_of(&name).to_string());
+        }
+    }
+    Ok(replicas)
+}
+
+/// Wraps another transport, encrypting everything on its way to the remote
+/// and checking and decrypting it on the way back, so the remote only ever
+/// sees sealed data. Sealed blobs are named by a keyed hash of their contents,
+/// and kept in `scratch` so that each one only needs to be encrypted once.
+/// Anything that fails to decrypt is refused.
+///
+/// The key is derived with a random salt kept in the header of the remote,
+/// which the first replica to encrypt it creates. That replica also moves
+/// whatever was pushed before the remote was encrypted, through another
+/// transport, over to the encrypted remote. Nothing is read from there after
+/// that.
+pub struct Encrypted {
+    inner: Box<dyn Transport>,
+    plain: Box<dyn Transport>,
+    key: Key,
+    scratch: PathBuf,
+    /// Whether the plain objects were moved over when this was opened.
+    migrated: bool,
+    refused: RefCell<Vec<String>>,
+}
+
+impl Encrypted {
+    pub fn new(
+        inner: Box<dyn Transport>,
+        plain: Box<dyn Transport>,
+        secret: &[u8],
+        scratch: &Path,
+    ) -> Result<Encrypted, Error> {
+        let header = scratch.join("header");
+        if !inner.pull_header(&header)? {
+            return Encrypted::create(inner, plain, secret, scratch);
+        }
+        Encrypted::with_header(inner, plain, secret, scratch, &read(&header)?)
+    }
+
+    /// Open the remote without changing anything there, or return `None` if
+    /// it has no header yet.
+    pub fn open(
+        inner: Box<dyn Transport>,
+        plain: Box<dyn Transport>,
+        secret: &[u8],
+        scratch: &Path,
+    ) -> Result<Option<Encrypted>, Error> {
+        let header = scratch.join("header");
+        if !inner.pull_header(&header)? {
+            return Ok(None);
+        }
+        let header = read(&header)?;
+        Encrypted::with_header(inner, plain, secret, scratch, &header).map(Some)
+    }
+
+    /// Create the header of the remote, and move the plain objects over.
+    fn create(
+        inner: Box<dyn Transport>,
+        plain: Box<dyn Transport>,
+        secret: &[u8],
+        scratch: &Path,
+    ) -> Result<Encrypted, Error> {
+        let header = scratch.join("header");
+        log::info!("creating the header of the encrypted remote");
+        let mut salt = [0u8; SALT_LEN];
+        crypto::random_bytes(&mut salt)?;
+        let mut created = SALT_MAGIC.to_vec();
+        created.extend_from_slice(&salt);
+        write_atomic(&header, |out| Ok(out.write_all(&created)?))?;
+
+        // The plain objects are moved over before the header is pushed, so
+        // that a migration cut short is started over by the next replica.
+        let mut encrypted = Encrypted::with_header(inner, plain, secret, scratch, &created)?;
+        encrypted.copy_plain()?;
+
+        // Another replica may have pushed its own header in the meantime,
+        // in which case that is the one to use.
+        encrypted.inner.push_header(&header)?;
+        if !encrypted.inner.pull_header(&header)? {
+            return Err(format_err!("remote has no header after pushing one"));
+        }
+        let pushed = read(&header)?;
+        if pushed != created {
+            log::info!("another replica created the header first");
+            let Encrypted { inner, plain, .. } = encrypted;
+            encrypted = Encrypted::with_header(inner, plain, secret, scratch, &pushed)?;
+            encrypted.copy_plain()?;
+        }
+        encrypted.remove_plain()?;
+        encrypted.migrated = true;
+        Ok(encrypted)
+    }
+
+    fn with_header(
+        inner: Box<dyn Transport>,
+        plain: Box<dyn Transport>,
+        secret: &[u8],
+        scratch: &Path,
+        header: &[u8],
+    ) -> Result<Encrypted, Error> {
+        let salt = match header.strip_prefix(SALT_MAGIC) {
+            Some(salt) if salt.len() == SALT_LEN => salt,
+            _ => return Err(format_err!("malformed header on the encrypted remote")),
+        };
+        Ok(Encrypted {
+            inner,
+            plain,
+            key: Key::derive(secret, salt),
+            scratch: scratch.to_path_buf(),
+            migrated: false,
+            refused: RefCell::new(Vec::new()),
+        })
+    }
+
+    fn replicas(&self) -> PathBuf {
+        self.scratch.join("replicas")
+    }
+
+    fn outgoing(&self) -> PathBuf {
+        self.scratch.join("outgoing")
+    }
+
+    fn blobs(&self) -> PathBuf {
+        self.scratch.join("blobs")
+    }
+
+    /// Where the plain objects left on the remote are pulled to while they
+    /// are moved over.
+    fn legacy(&self) -> PathBuf {
+        self.scratch.join("legacy")
+    }
+
+    /// A directory that is always empty, for removing files from a remote.
+    fn empty(&self) -> Result<PathBuf, Error> {
+        let empty = self.scratch.join("empty");
+        create_dir_all(&empty)?;
+        Ok(empty)
+    }
+
+    /// Seal `src` as `name` into `dest`.
+    fn seal_file(&self, src: &Path, dest: &Path, name: &str) -> Result<(), Error> {
+        let sealed = crypto::seal(&self.key, name.as_bytes(), &read(src)?)?;
+        if let Some(parent) = dest.parent() {
+            create_dir_all(parent)?;
+        }
+        write_atomic(dest, |out| Ok(out.write_all(&sealed)?))
+    }
+
+    /// Open `src`, which was sealed as `name`, or refuse it if it cannot be
+    /// decrypted.
+    fn open_file(&self, src: &Path, name: &str, what: &str) -> Result<Option<Vec<u8>>, Error> {
+        match crypto::open(&self.key, name.as_bytes(), &read(src)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) => {
+                log::error!("refusing {}: cannot decrypt {}: {}", what, name, e);
+                self.refused
+                    .borrow_mut()
+                    .push(format!("{}: cannot decrypt {}: {}", what, name, e));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Seal and push every state file and blob pushed in the clear.
+    fn copy_plain(&self) -> Result<(), Error> {
+        let legacy = self.legacy();
+        create_dir_all(&legacy)?;
+        self.plain.pull(&legacy)?;
+        let replicas = replicas_in(&legacy)?;
+        for replica in replicas.iter() {
+            log::info!("encrypting the state of replica {}", replica);
+            self.push(&legacy, replica)?;
+        }
+
+        let hashes = self
+            .plain
+            .list_blobs()?
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect::<Vec<Hash>>();
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        log::info!("encrypting {} blobs", hashes.len());
+        let incoming = BlobStore::new(&self.scratch.join("legacy-incoming"));
+        let store = BlobStore::new(&self.scratch.join("legacy-blobs"));
+        self.plain.pull_blobs(&hashes, incoming.dir())?;
+        for hash in hashes.iter() {
+            let path = incoming.path(hash);
+            if path.is_file() && !store.import(hash, &path)? {
+                log::warn!("leaving out damaged blob {}", hash);
+            }
+        }
+        self.push_blobs(&hashes, store.dir())?;
+        remove_dir_all(store.dir())?;
+        Ok(())
+    }
+
+    /// Remove every state file and blob pushed in the clear.
+    fn remove_plain(&self) -> Result<(), Error> {
+        let empty = self.empty()?;
+        for replica in replicas_in(&self.legacy())? {
+            self.plain.push(&empty, &replica)?;
+        }
+        let hashes = self
+            .plain
+            .list_blobs()?
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect::<Vec<Hash>>();
+        self.plain.remove_blobs(&hashes, &empty)
+    }
+}
+
+impl Transport for Encrypted {
+    fn pull(&self, dir: &Path) -> Result<(), Error> {
+        let sealed = self.replicas();
+        create_dir_all(&sealed)?;
+        self.inner.pull(&sealed)?;
+        mirror(
+            &sealed,
+            dir,
+            |_| true,
+            |path| {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                self.open_file(path, &name, replica_of(&name))
+            },
+        )
+    }
+
+    fn push(&self, dir: &Path, replica: &str) -> Result<(), Error> {
+        let outgoing = self.outgoing();
+        mirror(
+            dir,
+            &outgoing,
+            |name| is_replica_file(name, replica),
+            |path| {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                Ok(Some(crypto::seal(
+                    &self.key,
+                    name.as_bytes(),
+                    &read(path)?,
+                )?))
+            },
+        )?;
+        self.inner.push(&outgoing, replica)
+    }
+
+    fn pull_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error> {
+        let sealed = BlobStore::new(&self.blobs());
+        let store = BlobStore::new(dir);
+        let names = hashes.iter().map(|h| self.blob_name(h)).collect::<Vec<_>>();
+        self.inner.pull_blobs(&names, sealed.dir())?;
+
+        for (hash, name) in hashes.iter().zip(names.iter()) {
+            let dest = store.path(hash);
+            let path = sealed.path(name);
+            if dest.is_file() || !path.is_file() {
+                continue;
+            }
+            match self.open_file(&path, &name.to_string(), "blob")? {
+                Some(blob) => {
+                    if let Some(shard) = dest.parent() {
+                        create_dir_all(shard)?;
+                    }
+                    write_atomic(&dest, |out| Ok(out.write_all(&blob)?))?
+                }
+                None => remove_file(&path)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn push_blobs(&self, hashes: &[Hash], dir: &Path) -> Result<(), Error> {
+        let sealed = BlobStore::new(&self.blobs());
+        let store = BlobStore::new(dir);
+        let mut names = Vec::new();
+        for hash in hashes {
+            let name = self.blob_name(hash);
+            let src = store.path(hash);
+            let dest = sealed.path(&name);
+            if src.is_file() && !dest.is_file() {
+                self.seal_file(&src, &dest, &name.to_string())?;
+            }
+            names.push(name);
+        }
+        self.inner.push_blobs(&names, sealed.dir())
+    }
+
+    fn list_blobs(&self) -> Result<Vec<(Hash, u64)>, Error> {
+        self.inner.list_blobs()
+    }
+
+    fn remove_blobs(&self, names: &[Hash], _dir: &Path) -> Result<(), Error> {
+        // Sealed copies are dropped as well, or they would be pushed again.
+        let sealed = BlobStore::new(&self.blobs());
+        for name in names {
+            sealed.remove(name)?;
+        }
+        self.inner.remove_blobs(names, sealed.dir())
+    }
+
+    fn blob_name(&self, hash: &Hash) -> Hash {
+        Hash::from_bytes(self.key.name(hash.as_bytes()))
+    }
+
+    fn pull_header(&self, path: &Path) -> Result<bool, Error> {
+        self.inner.pull_header(path)
+    }
+
+    fn push_header(&self, path: &Path) -> Result<(), Error> {
+        self.inner.push_header(path)
+    }
+
+    /// The first releases never encrypted anything, so their state is only
+    /// read by the replica that moves the plain objects over, to be passed
+    /// on to the rest sealed.
+    fn pull_legacy(&self, path: &Path) -> Result<bool, Error> {
+        if !self.migrated {
+            return Ok(false);
+        }
+        self.plain.pull_legacy(path)
+    }
+
+    fn take_refused(&self) -> Vec<String> {
+        std::mem::take(&mut *self.refused.borrow_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// The names of the files in `dir`, sorted.
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names = read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn store(dir: &Path, blobs: &[&[u8]]) -> (BlobStore, Vec<Hash>) {
+        let store = BlobStore::new(dir);
+        let hashes = blobs.iter().map(|b| store.insert(*b).unwrap()).collect();
+        (store, hashes)
+    }
+
+    #[test]
+    fn local_dir_round_trips_state() {
+        let root = TempDir::new();
+        let remote = LocalDir::new(&root.path().join("remote"));
+        let outgoing = root.path().join("outgoing");
+        let incoming = root.path().join("incoming");
+        create_dir_all(&outgoing).unwrap();
+        for name in ["r1.cbor", "r1.x.delta", "r2.cbor"] {
+            write(outgoing.join(name), name).unwrap();
+        }
+
+        remote.push(&outgoing, "r1").unwrap();
+        remote.pull(&incoming).unwrap();
+        assert_eq!(names(&incoming), ["r1.cbor", "r1.x.delta"]);
+        assert_eq!(read(incoming.join("r1.x.delta")).unwrap(), b"r1.x.delta");
+
+        remote.push(&outgoing, "r2").unwrap();
+        remove_file(outgoing.join("r1.x.delta")).unwrap();
+        write(outgoing.join("r1.cbor"), "replaced").unwrap();
+        remote.push(&outgoing, "r1").unwrap();
+        remote.pull(&incoming).unwrap();
+        assert_eq!(names(&incoming), ["r1.cbor", "r2.cbor"]);
+        assert_eq!(read(incoming.join("r1.cbor")).unwrap(), b"replaced");
+
+        // Pushing a replica with no files left removes it from the remote.
+        let empty = root.path().join("empty");
+        create_dir_all(&empty).unwrap();
+        remote.push(&empty, "r1").unwrap();
+        remote.pull(&incoming).unwrap();
+        assert_eq!(names(&incoming), ["r2.cbor"]);
+    }
+
+    #[test]
+    fn local_dir_round_trips_blobs() {
+        let root = TempDir::new();
+        let remote = LocalDir::new(&root.path().join("remote"));
+        assert!(remote.list_blobs().unwrap().is_empty());
+
+        let (local, hashes) = store(&root.path().join("blobs"), &[b"one", b"three"]);
+        remote.push_blobs(&hashes, local.dir()).unwrap();
+        let mut listed = remote.list_blobs().unwrap();
+        listed.sort();
+        let mut expected = vec![(hashes[0], 3), (hashes[1], 5)];
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        let incoming = root.path().join("incoming");
+        create_dir_all(&incoming).unwrap();
+        let absent = Hash::of(b"absent");
+        remote.pull_blobs(&[hashes[1], absent], &incoming).unwrap();
+        let incoming = BlobStore::new(&incoming);
+        assert_eq!(incoming.list().unwrap(), [(hashes[1], 5)]);
+        assert_eq!(read(incoming.path(&hashes[1])).unwrap(), b"three");
+
+        // Blobs are kept in subdirectories named after their hashes.
+        let shard = &hashes[0].to_string()[..2];
+        let remote_blobs = root.path().join("remote.blobs");
+        assert!(names(&remote_blobs).iter().any(|name| name == shard));
+        assert!(remote_blobs.join(blob_path(&hashes[0])).is_file());
+
+        remote.remove_blobs(&hashes[..1], incoming.dir()).unwrap();
+        assert_eq!(remote.list_blobs().unwrap(), [(hashes[1], 5)]);
+    }
+
+    #[test]
+    fn local_dir_keeps_the_first_header() {
+        let root = TempDir::new();
+        let remote = LocalDir::new(&root.path().join("remote"));
+        let header = root.path().join("header");
+        assert!(!remote.pull_header(&header).unwrap());
+        assert!(!remote.pull_legacy(&root.path().join("legacy")).unwrap());
+
+        write(&header, "first").unwrap();
+        remote.push_header(&header).unwrap();
+        write(&header, "second").unwrap();
+        remote.push_header(&header).unwrap();
+        assert!(remote.pull_header(&header).unwrap());
+        assert_eq!(read(&header).unwrap(), b"first");
+    }
+
+    #[test]
+    fn recognizes_rsync_remotes() {
+        for url in ["host:library", "user@host:/srv/library", "host:"] {
+            assert!(is_rsync_remote(url), "{}", url);
+        }
+        for url in ["library", "/srv/library", "./a:b", "/mnt/usb:stick/library"] {
+            assert!(!is_rsync_remote(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_schemes() {
+        assert!(from_url("file:///srv/library").is_ok());
+        assert!(from_url("rsync://host/library").is_ok());
+        let e = from_url("s3://bucket/library").err().unwrap();
+        assert!(e.to_string().contains("s3"), "{}", e);
+    }
+
+    fn encrypted(root: &Path, secret: &[u8], scratch: &str) -> Encrypted {
+        let url = root.join("remote");
+        create_dir_all(root.join(scratch)).unwrap();
+        Encrypted::new(
+            Box::new(LocalDir::new(&with_suffix(&url, ".sealed"))),
+            Box::new(LocalDir::new(&url)),
+            secret,
+            &root.join(scratch),
+        )
+        .unwrap()
+    }
+
+    /// Push a state file named `name` for `replica`, and a blob, through
+    /// `transport`.
+    fn push_one(root: &Path, transport: &dyn Transport, name: &str, blob: &[u8]) -> Hash {
+        let outgoing = root.join("outgoing");
+        create_dir_all(&outgoing).unwrap();
+        write(outgoing.join(name), format!("state of {}", name)).unwrap();
+        transport.push(&outgoing, replica_of(name)).unwrap();
+        let (local, hashes) = store(&root.join("blobs"), &[blob]);
+        transport.push_blobs(&hashes, local.dir()).unwrap();
+        hashes[0]
+    }
+
+    #[test]
+    fn encrypted_round_trips() {
+        let root = TempDir::new();
+        let a = encrypted(root.path(), b"secret", "a");
+        let b = encrypted(root.path(), b"secret", "b");
+        assert_eq!(a.blob_name(&Hash::of(b"x")), b.blob_name(&Hash::of(b"x")));
+        let hash = push_one(root.path(), &a, "r1.cbor", b"plain blob");
+
+        // Nothing reaches the remote in the clear, or under its own hash.
+        let sealed = with_suffix(&root.path().join("remote"), ".sealed");
+        let state = read(with_suffix(&sealed, ".replicas").join("r1.cbor")).unwrap();
+        assert!(!state.windows(5).any(|w| w == b"state"));
+        let listed = b.list_blobs().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_ne!(listed[0].0, hash);
+        assert_eq!(listed[0].0, b.blob_name(&hash));
+
+        let incoming = root.path().join("incoming");
+        b.pull(&incoming).unwrap();
+        assert_eq!(read(incoming.join("r1.cbor")).unwrap(), b"state of r1.cbor");
+        let fetched = BlobStore::new(&root.path().join("fetched"));
+        b.pull_blobs(&[hash], fetched.dir()).unwrap();
+        assert_eq!(read(fetched.path(&hash)).unwrap(), b"plain blob");
+        assert!(b.take_refused().is_empty());
+    }
+
+    #[test]
+    fn encrypted_refuses_what_it_cannot_open() {
+        let root = TempDir::new();
+        let a = encrypted(root.path(), b"secret", "a");
+        let b = encrypted(root.path(), b"wrong", "b");
+        let hash = push_one(root.path(), &a, "r1.cbor", b"plain blob");
+
+        let incoming = root.path().join("incoming");
+        b.pull(&incoming).unwrap();
+        assert!(names(&incoming).is_empty());
+
+        // A sealed blob that has been tampered with is refused as well.
+        let name = a.blob_name(&hash);
+        let sealed = BlobStore::new(&root.path().join("remote.sealed.blobs"));
+        write(sealed.path(&name), "tampered").unwrap();
+        remove_file(BlobStore::new(&a.blobs()).path(&name)).unwrap();
+        let fetched = BlobStore::new(&root.path().join("fetched"));
+        a.pull_blobs(&[hash], fetched.dir()).unwrap();
+        assert!(!fetched.contains(&hash));
+
+        let refused = b.take_refused();
+        assert_eq!(refused.len(), 1);
+        assert!(refused[0].starts_with("r1: "), "{}", refused[0]);
+        let refused = a.take_refused();
+        assert_eq!(refused.len(), 1);
+        assert!(refused[0].starts_with("blob: "), "{}", refused[0]);
+        assert!(a.take_refused().is_empty());
+    }
+
+    #[test]
+    fn encrypted_moves_plain_objects_over_once() {
+        let root = TempDir::new();
+        let plain = LocalDir::new(&root.path().join("remote"));
+        let old = push_one(root.path(), &plain, "r1.cbor", b"old blob");
+        push_one(root.path(), &plain, "r2.cbor", b"other blob");
+        write(root.path().join("remote"), "legacy state").unwrap();
+
+        // The replica that encrypts the remote moves everything over, and
+        // alone reads the state left by the first releases.
+        let a = encrypted(root.path(), b"secret", "a");
+        let legacy = root.path().join("legacy");
+        assert!(a.pull_legacy(&legacy).unwrap());
+        let plain_incoming = root.path().join("plain");
+        plain.pull(&plain_incoming).unwrap();
+        assert!(names(&plain_incoming).is_empty());
+        assert!(plain.list_blobs().unwrap().is_empty());
+
+        let b = encrypted(root.path(), b"secret", "b");
+        assert!(!b.pull_legacy(&legacy).unwrap());
+        let incoming = root.path().join("incoming");
+        b.pull(&incoming).unwrap();
+        assert_eq!(names(&incoming), ["r1.cbor", "r2.cbor"]);
+        assert_eq!(read(incoming.join("r1.cbor")).unwrap(), b"state of r1.cbor");
+        let fetched = BlobStore::new(&root.path().join("fetched"));
+        b.pull_blobs(&[old], fetched.dir()).unwrap();
+        assert_eq!(read(fetched.path(&old)).unwrap(), b"old blob");
+
+        // Anything pushed in the clear from then on is ignored.
+        let planted = push_one(root.path(), &plain, "r3.cbor", b"planted blob");
+        write(incoming.join("r3.cbor"), "stale").unwrap();
+        b.pull(&incoming).unwrap();
+        assert_eq!(names(&incoming), ["r1.cbor", "r2.cbor"]);
+        b.pull_blobs(&[planted], fetched.dir()).unwrap();
+        assert!(!fetched.contains(&planted));
+        let c = encrypted(root.path(), b"secret", "c");
+        c.pull(&incoming).unwrap();
+        assert_eq!(names(&incoming), ["r1.cbor", "r2.cbor"]);
+    }
+}